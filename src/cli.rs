@@ -1,6 +1,8 @@
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand, arg};
+use clap::{Args, Parser, Subcommand};
+
+use crate::templater::{export::ArchiveFormat, merge::Conflict};
 
 #[derive(Debug, Parser)]
 #[command(version, about)]
@@ -35,17 +37,7 @@ pub enum Task {
         #[arg(short, long)]
         force: bool,
     },
-    Expand {
-        name: String,
-        #[arg(short, long)]
-        path: Option<PathBuf>,
-        #[arg(short, long = "env")]
-        envs: Vec<String>,  // --env key=value
-        #[arg(short = 'a', long = "as")]
-        create_as: Option<String>,
-        #[arg(short, long)]
-        no_exec: bool,
-    },
+    Expand(ExpandArgs),
     List {
         #[arg(short, long)]
         name: Option<String>,
@@ -57,7 +49,65 @@ pub enum Task {
     Delete {
         name: String,
     },
+    Diff {
+        name: String,
+        path: Option<PathBuf>,  // project directory, current one by default
+        #[arg(long = "values")]
+        values_file: Option<PathBuf>,  // on top of the ones from the project's manifest
+        #[arg(short, long)]
+        ignore: Vec<String>,
+        #[arg(short, long)]
+        summary: bool,  // only list changed files
+    },
+    Update {
+        path: Option<PathBuf>,  // project directory, current one by default
+        #[arg(long)]
+        reject: bool,  // keep conflicting files as they are, and put template changes to .rej files
+    },
     Edit {
         name: String,
     },
 }
+
+#[derive(Debug, Args)]
+pub struct ExpandArgs {
+    pub name: String,
+    #[arg(short, long)]
+    pub path: Option<PathBuf>,
+    #[arg(short, long = "env")]
+    pub envs: Vec<String>,  // --env key=value, or key to pass the current value through
+    #[arg(long = "env-file")]
+    pub env_files: Vec<PathBuf>,
+    #[arg(short = 'a', long = "as")]
+    pub create_as: Option<String>,
+    #[arg(short, long)]
+    pub no_exec: bool,
+    #[arg(long = "values")]
+    pub values_file: Option<PathBuf>,  // json or toml, - for stdin
+    #[arg(long)]
+    pub save_values: Option<PathBuf>,
+    #[arg(long)]
+    pub keep_on_failure: bool,
+    #[arg(long, conflicts_with_all = ["path", "create_as", "keep_on_failure"])]
+    pub into: Option<PathBuf>,  // existing directory to add the template to
+    #[arg(long, value_enum, requires = "into")]
+    pub on_conflict: Option<Conflict>,
+    #[arg(long)]
+    pub dry_run: bool,
+    #[arg(long)]
+    pub only: Vec<String>,  // globs of template paths to expand, everything else is skipped
+    #[arg(long)]
+    pub exclude: Vec<String>,
+    #[arg(long, conflicts_with_all = ["into", "keep_on_failure"])]
+    pub output_archive: Option<PathBuf>,  // .tar, .tar.gz or .zip file to write the project to, - for stdout
+    #[arg(long, value_enum, requires = "output_archive")]
+    pub archive_format: Option<ArchiveFormat>,
+    #[arg(long)]
+    pub no_manifest: bool,  // don't write .templater.json into the project
+    #[arg(long, value_delimiter = ',')]
+    pub steps: Vec<String>,  // ids of optional steps to run
+    #[arg(long, conflicts_with = "steps")]
+    pub all_steps: bool,
+    #[arg(short, long)]
+    pub yes: bool,  // run commands without asking
+}
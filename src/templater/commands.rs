@@ -0,0 +1,499 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::{Child, ExitStatus, Stdio},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use super::{
+    error::Error,
+    output::CommandLog,
+    render::{safe_join, Renderer},
+    scripts::Script,
+    selection::Platform,
+};
+
+// Plain strings go through the platform shell untouched, so quoting, pipes and `&&` work the
+// way they would when typed by hand. The structured form runs the program directly instead
+#[derive(Debug, Clone)]
+pub enum TemplateCommand {
+    Shell(String),
+    Program { program: String, args: Vec<String> },
+}
+
+// Keys of StepOptions, including the flattened platform - keep in sync with the fields
+const OPTION_KEYS: &[&str] = &[
+    "cwd", "env", "when", "timeout", "continue_on_error", "os", "family", "optional", "id", "prompt",
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StepOptions {
+    // Relative to the project root, rendered like file names
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    // Expression over template variables
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>,
+    // In seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub continue_on_error: bool,
+    #[serde(flatten)]
+    pub platform: Platform,
+    // Optional steps only run when chosen, by their id
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+}
+
+// Steps of the other phases - the template's `commands` run after unpacking
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Hooks {
+    // In the source directory, before it's archived
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_create: Vec<Step>,
+    // In the still empty project directory, before anything is unpacked
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_expand: Vec<Step>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.pre_create.is_empty() && self.pre_expand.is_empty()
+    }
+
+    pub fn check(&self) -> Result<()> {
+        for step in self.pre_create.iter().chain(&self.pre_expand) {
+            step.check()?;
+        }
+        // Nobody is asked about them while creating
+        if let Some(step) = self.pre_create.iter().find(|step| step.options.optional) {
+            return Err(Error::InvalidArgument(format!(
+                "Pre-create hooks can't be optional: {}",
+                step.command
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "Value", into = "StepRepr")]
+pub struct Step {
+    pub command: TemplateCommand,
+    pub options: StepOptions,
+    // Keys serde would silently drop, reported by `check` - a typo in `timeout` or `args`
+    // shouldn't change what runs
+    unknown: Vec<String>,
+}
+
+// What steps look like in definition files and metadata. Steps without options are kept as
+// plain strings, so older metadata stays readable both ways
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StepRepr {
+    Line(String),
+    Shell {
+        run: String,
+        #[serde(flatten)]
+        options: StepOptions,
+    },
+    Program {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(flatten)]
+        options: StepOptions,
+    },
+}
+
+impl From<StepRepr> for Step {
+    fn from(repr: StepRepr) -> Self {
+        match repr {
+            StepRepr::Line(line) => Step::from(TemplateCommand::Shell(line)),
+            StepRepr::Shell { run, options } => Step {
+                command: TemplateCommand::Shell(run),
+                options,
+                unknown: Vec::new(),
+            },
+            StepRepr::Program {
+                program,
+                args,
+                options,
+            } => Step {
+                command: TemplateCommand::Program { program, args },
+                options,
+                unknown: Vec::new(),
+            },
+        }
+    }
+}
+
+// Untagged StepRepr ignores whatever it doesn't know, so keys are compared with the known ones
+// before it gets to see them
+impl TryFrom<Value> for Step {
+    type Error = serde_json::Error;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let unknown = match &value {
+            Value::Object(map) => {
+                let command_keys: &[&str] = match map.contains_key("program") {
+                    true => &["program", "args"],
+                    false => &["run"],
+                };
+                map.keys()
+                    .filter(|key| {
+                        !command_keys.contains(&key.as_str()) && !OPTION_KEYS.contains(&key.as_str())
+                    })
+                    .cloned()
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        let mut step = Step::from(serde_json::from_value::<StepRepr>(value)?);
+        step.unknown = unknown;
+        Ok(step)
+    }
+}
+
+impl From<Step> for StepRepr {
+    fn from(step: Step) -> Self {
+        match step.command {
+            TemplateCommand::Shell(line) if step.options == StepOptions::default() => {
+                StepRepr::Line(line)
+            }
+            TemplateCommand::Shell(run) => StepRepr::Shell {
+                run,
+                options: step.options,
+            },
+            TemplateCommand::Program { program, args } => StepRepr::Program {
+                program,
+                args,
+                options: step.options,
+            },
+        }
+    }
+}
+
+impl From<String> for Step {
+    fn from(line: String) -> Self {
+        StepRepr::Line(line).into()
+    }
+}
+
+impl From<TemplateCommand> for Step {
+    fn from(command: TemplateCommand) -> Self {
+        Step {
+            command,
+            options: StepOptions::default(),
+            unknown: Vec::new(),
+        }
+    }
+}
+
+impl Step {
+    pub fn check(&self) -> Result<()> {
+        if !self.unknown.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "Command {} has unknown options: {}",
+                self.command,
+                self.unknown.join(", ")
+            ))
+            .into());
+        }
+        self.command.check()?;
+        if let Some(cwd) = &self.options.cwd {
+            safe_join(Path::new("."), cwd)?;
+        }
+        if let Some(when) = &self.options.when {
+            Renderer::check_expression(when)?;
+        }
+        if self.options.optional && self.options.id.is_none() {
+            return Err(Error::InvalidArgument(format!(
+                "Optional command needs an id: {}",
+                self.command
+            ))
+            .into());
+        }
+        self.options.platform.check()
+    }
+
+    // Output is shown while the command runs, with the program name in front of every line
+    pub fn run(
+        &self,
+        cwd: &Path,
+        envs: &HashMap<String, String>,
+        log: &CommandLog,
+    ) -> Result<ExitStatus> {
+        let mut process = self.command.to_process();
+        process
+            .current_dir(cwd)
+            .envs(envs.iter())
+            .envs(self.options.env.iter())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if self.options.timeout.is_some() {
+            own_group(&mut process);
+        }
+
+        log.begin(&self.command.to_string(), cwd)?;
+        let mut child = process
+            .spawn()
+            .context(format!("Failed to start command: {}", self.command))?;
+        let prefix = self.command.program_name();
+        let mut readers = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            readers.push(log.forward(stdout, prefix.clone(), false));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            readers.push(log.forward(stderr, prefix, true));
+        }
+        let join = |readers: Vec<JoinHandle<()>>| {
+            for reader in readers {
+                let _ = reader.join();
+            }
+        };
+
+        let timeout = match self.options.timeout {
+            Some(timeout) => timeout,
+            None => {
+                let status = child.wait()?;
+                join(readers);
+                return Ok(status);
+            }
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        loop {
+            if let Some(status) = child.try_wait()? {
+                join(readers);
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                kill_tree(&mut child)?;
+                child.wait()?;
+                // Anything that left the group may still hold the pipes, so readers are left be
+                return Err(log.failure(Error::CommandTimeout(self.command.to_string(), timeout)));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+// Identifies commands by their content, so approvals don't survive changes to them
+pub fn digest<'a>(steps: impl IntoIterator<Item = &'a Step>, scripts: &[Script]) -> Result<String> {
+    let steps = steps.into_iter().collect::<Vec<&Step>>();
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&steps).context("Failed to serialize commands")?);
+    for script in scripts {
+        hasher.update(script.name.as_bytes());
+        hasher.update(&script.data);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl TemplateCommand {
+    pub fn check(&self) -> Result<()> {
+        let program = match self {
+            TemplateCommand::Shell(line) => shell_words::split(line)
+                .context(format!("Invalid command: {}", line))?
+                .into_iter()
+                .next(),
+            TemplateCommand::Program { program, .. } => Some(program.clone()),
+        };
+        match program {
+            Some(program) if !program.trim().is_empty() => Ok(()),
+            _ => Err(Error::InvalidArgument(format!("Command without a program: {}", self)).into()),
+        }
+    }
+
+    pub fn program_name(&self) -> String {
+        let program = match self {
+            TemplateCommand::Shell(line) => shell_words::split(line)
+                .ok()
+                .and_then(|words| words.into_iter().next())
+                .map(|word| word.trim_end_matches([';', '&', '|']).to_string())
+                .unwrap_or_else(|| line.clone()),
+            TemplateCommand::Program { program, .. } => program.clone(),
+        };
+        Path::new(&program)
+            .file_name()
+            .map_or(program.clone(), |name| name.to_string_lossy().to_string())
+    }
+
+    pub fn to_process(&self) -> std::process::Command {
+        match self {
+            TemplateCommand::Shell(line) => shell(line),
+            TemplateCommand::Program { program, args } => {
+                let mut process = std::process::Command::new(program);
+                process.args(args);
+                process
+            }
+        }
+    }
+}
+
+impl Display for TemplateCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateCommand::Shell(line) => write!(f, "{}", line),
+            TemplateCommand::Program { program, args } => {
+                write!(f, "{}", shell_words::join(std::iter::once(program).chain(args)))
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+fn shell(line: &str) -> std::process::Command {
+    use std::os::windows::process::CommandExt;
+
+    // cmd has its own quoting rules, escaping the line for CreateProcess would only mangle it
+    let mut process = std::process::Command::new("cmd");
+    process.arg("/C").raw_arg(line);
+    process
+}
+
+#[cfg(not(windows))]
+fn shell(line: &str) -> std::process::Command {
+    let mut process = std::process::Command::new("sh");
+    process.arg("-c").arg(line);
+    process
+}
+
+// Steps with a timeout get a process group of their own, so whatever they start can be stopped
+// along with them. Only those - a background group can't read from the terminal
+#[cfg(windows)]
+fn own_group(_process: &mut std::process::Command) {}
+
+#[cfg(not(windows))]
+fn own_group(process: &mut std::process::Command) {
+    use std::os::unix::process::CommandExt;
+
+    process.process_group(0);
+}
+
+// Killing only the child would leave everything it started running, like the build `sh -c`
+// was waiting for
+#[cfg(windows)]
+fn kill_tree(child: &mut Child) -> Result<()> {
+    let killed = std::process::Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &child.id().to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    if !matches!(killed, Ok(status) if status.success()) {
+        child.kill()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn kill_tree(child: &mut Child) -> Result<()> {
+    // Group is named after the pid of the process it was made for
+    let killed = std::process::Command::new("kill")
+        .args(["-KILL", "--", &format!("-{}", child.id())])
+        .stderr(Stdio::null())
+        .status();
+    if !matches!(killed, Ok(status) if status.success()) {
+        child.kill()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(json: &str) -> Step {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn option_keys_match_fields() {
+        let options = StepOptions {
+            cwd: Some(PathBuf::from("a")),
+            env: BTreeMap::from([("A".to_string(), "1".to_string())]),
+            when: Some("true".to_string()),
+            timeout: Some(1),
+            continue_on_error: true,
+            platform: Platform {
+                os: vec!["linux".to_string()],
+                family: vec!["unix".to_string()],
+            },
+            optional: true,
+            id: Some("a".to_string()),
+            prompt: Some("A?".to_string()),
+        };
+        let value = serde_json::to_value(&options).unwrap();
+        let mut keys = value.as_object().unwrap().keys().cloned().collect::<Vec<_>>();
+        let mut expected = OPTION_KEYS.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        keys.sort();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn misspelled_option_fails_check() {
+        let error = step(r#"{"run": "make", "timout": 5}"#).check().unwrap_err();
+        assert!(error.to_string().contains("timout"), "{}", error);
+        assert!(step(r#"{"run": "make", "timeout": 5}"#).check().is_ok());
+    }
+
+    #[test]
+    fn program_with_run_fails_check() {
+        assert!(step(r#"{"program": "make", "run": "make all"}"#).check().is_err());
+        assert!(step(r#"{"program": "make", "args": ["all"]}"#).check().is_ok());
+    }
+
+    #[test]
+    fn plain_step_stays_a_string() {
+        let line = r#""cargo build && cargo test""#;
+        let step = step(line);
+        assert!(matches!(&step.command, TemplateCommand::Shell(run) if run.contains("&&")));
+        assert_eq!(serde_json::to_string(&step).unwrap(), line);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn timeout_stops_what_the_step_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let step: Step = serde_json::from_str(
+            r#"{"run": "sleep 30 & echo $! > pid; wait", "timeout": 1}"#,
+        )
+        .unwrap();
+        let log = CommandLog::new(dir.path().join("logs"));
+        let error = step.run(dir.path(), &HashMap::new(), &log).unwrap_err();
+        assert!(error.to_string().contains("timed out"), "{}", error);
+
+        let pid = std::fs::read_to_string(dir.path().join("pid")).unwrap();
+        let alive = || {
+            let state = std::process::Command::new("ps")
+                .args(["-o", "stat=", "-p", pid.trim()])
+                .output()
+                .unwrap()
+                .stdout;
+            // Nobody may be around to reap it, but a zombie doesn't run anymore
+            !state.is_empty() && !state.starts_with(b"Z")
+        };
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while alive() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!alive(), "sleep {} is still running", pid.trim());
+    }
+}
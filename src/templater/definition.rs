@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+use super::{
+    commands::{Hooks, Step},
+    environment,
+    merge::Conflict,
+    error::Error,
+    render::{RenderRules, Renderer},
+    selection::{self, Platform},
+    variables::Variable,
+};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TemplateDefinition {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub commands: Vec<Step>,
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+    // Environment variables commands can't do without
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_env: Vec<String>,
+    // Only used when creating, so there is no point in showing it while editing
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+    #[serde(default)]
+    pub variables: Vec<Variable>,
+    // glob -> expression over variables; matching entries are only expanded when it's true
+    #[serde(default)]
+    pub conditions: BTreeMap<String, String>,
+    // glob -> platforms the matching entries are expanded on
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub platforms: BTreeMap<String, Platform>,
+    #[serde(default)]
+    pub render: RenderRules,
+    // glob -> what to do with existing files, when expanding --into a directory
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub conflicts: BTreeMap<String, Conflict>,
+}
+
+impl TemplateDefinition {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .context(format!("Couldn't read definition file {}", path.display()))?;
+        serde_json::from_str(&contents).context("Provided file is not valid definition file")
+    }
+
+    // Whatever was given on the command line takes precedence over the definition file
+    pub fn merge(self, file: TemplateDefinition) -> Self {
+        TemplateDefinition {
+            name: self.name.or(file.name),
+            description: self.description.or(file.description),
+            commands: if self.commands.is_empty() {
+                file.commands
+            } else {
+                self.commands
+            },
+            ignore: if self.ignore.is_empty() {
+                file.ignore
+            } else {
+                self.ignore
+            },
+            // These can only come from the file
+            hooks: file.hooks,
+            required_env: file.required_env,
+            variables: file.variables,
+            conditions: file.conditions,
+            platforms: file.platforms,
+            render: file.render,
+            conflicts: file.conflicts,
+        }
+    }
+
+    pub fn check(&self) -> Result<()> {
+        for step in &self.commands {
+            step.check()?;
+        }
+        self.hooks.check()?;
+        let mut ids = HashSet::new();
+        let steps = self.hooks.pre_create.iter().chain(&self.hooks.pre_expand);
+        for id in steps.chain(&self.commands).filter_map(|step| step.options.id.as_ref()) {
+            if !ids.insert(id) {
+                return Err(Error::InvalidArgument(format!("Step id {} is used more than once", id)).into());
+            }
+        }
+        for name in &self.required_env {
+            environment::check_name(name)
+                .map_err(|reason| Error::InvalidEnv(name.clone(), reason))?;
+        }
+
+        let mut declared = HashSet::new();
+        for variable in &self.variables {
+            if !declared.insert(&variable.name) {
+                return Err(Error::InvalidVariable(
+                    variable.name.clone(),
+                    "declared more than once".to_string(),
+                )
+                .into());
+            }
+            variable.check()?;
+        }
+        for (pattern, condition) in &self.conditions {
+            selection::glob(pattern)?;
+            Renderer::check_expression(condition)?;
+        }
+        for pattern in self.conflicts.keys() {
+            selection::glob(pattern)?;
+        }
+        for (pattern, platform) in &self.platforms {
+            selection::glob(pattern)?;
+            platform.check()?;
+        }
+        self.render.check()
+    }
+}
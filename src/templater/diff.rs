@@ -0,0 +1,163 @@
+use anyhow::{Context, Result};
+use globset::GlobMatcher;
+use walkdir::WalkDir;
+
+use std::{
+    collections::BTreeMap,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    error::Error,
+    manifest::{Manifest, MANIFEST_FILE},
+    merge,
+    render::Variables,
+    selection, variables, Template, Templater,
+};
+
+impl Templater {
+    // Template is rendered in memory, without running anything, and compared with the project:
+    // `a/` is the template, `b/` the project, so added files are the ones only the project has
+    pub(super) fn diff_project(
+        &self,
+        name: &str,
+        dir: &Path,
+        values_file: Option<&Path>,
+        ignore: &[String],
+        summary: bool,
+    ) -> Result<()> {
+        let template: Template = match self.db.get(name)? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => return Err(Error::TemplateNotFound(name.to_string()).into()),
+        };
+        if !dir.is_dir() {
+            return Err(Error::InvalidTemplateDir(dir.to_path_buf()).into());
+        }
+        let dir = dir
+            .canonicalize()
+            .context(format!("Failed to resolve path: {}", dir.display()))?;
+
+        let values = self.diff_values(&template, &dir, values_file)?;
+        let rendered = self.render_files(&template, &self.archive_path(name), values)?;
+        let project = project_files(&dir, ignore)?;
+
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        let mut modified = Vec::new();
+        for (path, (data, _)) in &rendered {
+            match project.get(path) {
+                Some(existing) if existing == data => {}
+                Some(existing) => {
+                    if !summary {
+                        merge::print_diff(path, data, existing);
+                    }
+                    modified.push(path);
+                }
+                None => {
+                    if !summary {
+                        merge::print_diff(path, data, &[]);
+                    }
+                    removed.push(path);
+                }
+            }
+        }
+        for (path, data) in &project {
+            if !rendered.contains_key(path) {
+                if !summary {
+                    merge::print_diff(path, &[], data);
+                }
+                added.push(path);
+            }
+        }
+
+        for (status, paths) in [("Added", &added), ("Removed", &removed), ("Modified", &modified)] {
+            for path in paths {
+                println!("{:<9} {}", status, path.display());
+            }
+        }
+        log::info!(
+            "Compared with revision {} of {}: {} added, {} removed, {} modified",
+            template.revision,
+            name,
+            added.len(),
+            removed.len(),
+            modified.len()
+        );
+        Ok(())
+    }
+
+    // Values the project was generated with, when it has a manifest of this template. Values file
+    // adds to them, or overrides them, and anything still missing is asked for
+    fn diff_values(
+        &self,
+        template: &Template,
+        dir: &Path,
+        values_file: Option<&Path>,
+    ) -> Result<Variables> {
+        let interactive = values_file.is_none() && std::io::stdin().is_terminal();
+        let manifest = match dir.join(MANIFEST_FILE).exists() {
+            true => Some(Manifest::load(dir)?),
+            false => None,
+        };
+
+        let mut values = match manifest {
+            Some(manifest) if manifest.template == template.name => {
+                if !self.is_origin(&manifest, template)? {
+                    log::warn!(
+                        "Project wasn't expanded from revision {} of {} stored here, values it was \
+                         expanded with may not fit",
+                        manifest.revision,
+                        template.name
+                    );
+                }
+                manifest.variables
+            }
+            manifest => {
+                if let Some(manifest) = manifest {
+                    log::warn!(
+                        "Project was expanded from template {}, not {}",
+                        manifest.template,
+                        template.name
+                    );
+                }
+                variables::builtins(&template.name, template.revision, dir)
+            }
+        };
+        if let Some(values_file) = values_file {
+            values.extend(variables::load_answers(values_file)?);
+        }
+        // Secrets, and variables the template got since, aren't known yet
+        values.extend(variables::resolve(&template.variables, &values, interactive)?);
+        values.insert("template_version".to_string(), template.revision.into());
+        Ok(values)
+    }
+}
+
+// Every file of the project, except for its VCS data, the manifest and ignored paths (with
+// everything inside ignored directories)
+fn project_files(dir: &Path, ignore: &[String]) -> Result<BTreeMap<PathBuf, Vec<u8>>> {
+    let ignore = ignore
+        .iter()
+        .map(|pattern| selection::glob(pattern))
+        .collect::<Result<Vec<GlobMatcher>>>()?;
+
+    let mut files = BTreeMap::new();
+    let walker = WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != ".git");
+    for entry in walker {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(dir)?.to_path_buf();
+        if !entry.file_type().is_file()
+            || path == Path::new(MANIFEST_FILE)
+            || path.ancestors().any(|path| ignore.iter().any(|matcher| matcher.is_match(path)))
+        {
+            continue;
+        }
+        let data = std::fs::read(entry.path())
+            .context(format!("Failed to read file: {}", entry.path().display()))?;
+        files.insert(path, data);
+    }
+    Ok(files)
+}
@@ -0,0 +1,140 @@
+use anyhow::{Context, Result};
+
+use std::{collections::HashMap, path::Path};
+
+use super::error::Error;
+
+// `KEY=VALUE` sets the value, a bare `KEY` passes through the current one
+pub fn parse(entry: &str) -> Result<(String, String)> {
+    let (key, value) = match entry.split_once('=') {
+        Some((key, value)) => (key, value.to_string()),
+        None => {
+            let value = std::env::var(entry).map_err(|_| {
+                Error::InvalidEnv(entry.to_string(), "not set in current environment".to_string())
+            })?;
+            (entry, value)
+        }
+    };
+    check_name(key).map_err(|reason| Error::InvalidEnv(entry.to_string(), reason))?;
+    Ok((key.to_string(), value))
+}
+
+// Dotenv format: KEY=VALUE lines, optionally quoted or prefixed with `export`, and # comments
+pub fn load_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents = std::fs::read_to_string(path)
+        .context(format!("Couldn't read env file {}", path.display()))?;
+
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let location = format!("{}:{}", path.display(), number + 1);
+        let (key, value) = line.split_once('=').ok_or_else(|| {
+            Error::InvalidEnv(location.clone(), "expected KEY=VALUE".to_string())
+        })?;
+        let key = key.trim();
+        check_name(key).map_err(|reason| Error::InvalidEnv(location, reason))?;
+        entries.push((key.to_string(), unquote(value.trim()).to_string()));
+    }
+    Ok(entries)
+}
+
+// Names that are neither given, nor set in the current environment
+pub fn missing(required: &[String], envs: &HashMap<String, String>) -> Vec<String> {
+    required
+        .iter()
+        .filter(|name| !envs.contains_key(*name) && std::env::var_os(name).is_none())
+        .cloned()
+        .collect()
+}
+
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("name is empty".to_string());
+    }
+    if name.chars().any(|c| c.is_whitespace() || c == '=') {
+        return Err(format!("{} is not a valid name", name));
+    }
+    Ok(())
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_key_value() {
+        assert_eq!(parse("KEY=value").unwrap(), ("KEY".to_string(), "value".to_string()));
+        assert_eq!(parse("KEY=a=b").unwrap(), ("KEY".to_string(), "a=b".to_string()));
+        assert_eq!(parse("KEY=").unwrap(), ("KEY".to_string(), String::new()));
+        assert!(parse("=value").is_err());
+        assert!(parse("MY KEY=value").is_err());
+    }
+
+    #[test]
+    fn parse_passes_current_value_through() {
+        let (key, value) = parse("PATH").unwrap();
+        assert_eq!(key, "PATH");
+        assert_eq!(value, std::env::var("PATH").unwrap());
+        assert!(parse("TEMPLATER_SURELY_NOT_SET_ANYWHERE").is_err());
+    }
+
+    #[test]
+    fn load_dotenv_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let contents = concat!(
+            "# comment\n\n",
+            "A=1\n",
+            "export B = two words \n",
+            "C=\"quoted # not a comment\"\n",
+            "D='single'\n",
+            "E=\"unbalanced\n",
+        );
+        std::io::Write::write_all(&mut file, contents.as_bytes()).unwrap();
+        let entries = load_file(file.path()).unwrap();
+        let expected = [
+            ("A", "1"),
+            ("B", "two words"),
+            ("C", "quoted # not a comment"),
+            ("D", "single"),
+            ("E", "\"unbalanced"),
+        ];
+        assert_eq!(
+            entries,
+            expected
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn load_dotenv_file_reports_line() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"A=1\nnot an assignment\n").unwrap();
+        let error = load_file(file.path()).unwrap_err().to_string();
+        assert!(error.contains(&format!("{}:2", file.path().display())), "{}", error);
+    }
+
+    #[test]
+    fn missing_names() {
+        let envs = HashMap::from([("GIVEN".to_string(), "1".to_string())]);
+        let required = ["GIVEN", "PATH", "TEMPLATER_SURELY_NOT_SET_ANYWHERE"].map(String::from);
+        assert_eq!(missing(&required, &envs), vec!["TEMPLATER_SURELY_NOT_SET_ANYWHERE"]);
+    }
+}
@@ -10,8 +10,36 @@ pub enum Error {
     TemplateExists(String),
     #[error("Failed to create template: {0}")]
     CreateTemplate(String),
+    #[error("Command timed out after {1}s: {0}")]
+    CommandTimeout(String, u64),
+    #[error("Last output of the command (full log in {0}):\n{1}")]
+    CommandOutput(std::path::PathBuf, String),
+    #[error("Invalid environment variable {0}: {1}")]
+    InvalidEnv(String, String),
+    #[error("Required environment variables are not set: {}", .0.join(", "))]
+    MissingEnv(Vec<String>),
+    #[error("Revision {1} of template {0} is no longer available")]
+    RevisionNotFound(String, u32),
+    #[error("Project wasn't expanded from revision {1} of template {0} stored here, was the template deleted and created again?")]
+    TemplateMismatch(String, u32),
     #[error("Invalid argument: {0}")]
     InvalidArgument(String),
     #[error("Failed to edit template: {0}")]
     EditTemplate(String),
+    #[error("Invalid value for variable {0}: {1}")]
+    InvalidVariable(String, String),
+    #[error("No value provided for variable {0}")]
+    MissingVariable(String),
+    #[error("Some variables have no valid value:\n{}", .0.join("\n"))]
+    InvalidValues(Vec<String>),
+    #[error("{0}:{1}: {2}")]
+    RenderTemplate(String, usize, String),
+    #[error("Commands of template {0} are not trusted, review them and run with --yes to allow")]
+    UntrustedCommands(String),
+    #[error("Invalid output of hook script {0}: {1}")]
+    HookOutput(String, String),
+    #[error("Some files already exist, pick what to do with them with --on-conflict:\n{}", .0.join("\n"))]
+    FileConflict(Vec<String>),
+    #[error("Path escapes the project directory: {0}")]
+    UnsafePath(std::path::PathBuf),
 }
@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use flate2::{write::GzEncoder, Compression};
+use tar::{Builder, EntryType, Header};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use std::{
+    fs::File,
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use super::{error::Error, render::RenderedEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ArchiveFormat {
+    Tar,
+    #[value(name = "tar.gz")]
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    // Picked by extension, stdout gets a plain tar unless told otherwise
+    pub fn detect(path: &Path) -> Result<Self> {
+        if path == Path::new("-") {
+            return Ok(ArchiveFormat::Tar);
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar") {
+            Ok(ArchiveFormat::Tar)
+        } else if name.ends_with(".zip") {
+            Ok(ArchiveFormat::Zip)
+        } else {
+            Err(Error::InvalidArgument(format!(
+                "Unknown archive format of {}, pick one with --archive-format",
+                path.display()
+            ))
+            .into())
+        }
+    }
+}
+
+// Writes rendered project as an archive, with everything inside the `root` directory, so
+// unpacking it gives the same project a directory expansion would. `-` is stdout
+pub fn write(
+    output: &Path,
+    format: ArchiveFormat,
+    root: &str,
+    entries: Vec<RenderedEntry>,
+) -> Result<()> {
+    let root = Path::new(root);
+    let entries = std::iter::once(RenderedEntry::Directory(PathBuf::new()))
+        .chain(entries)
+        .map(|entry| match entry {
+            RenderedEntry::Directory(path) => RenderedEntry::Directory(root.join(path)),
+            RenderedEntry::File { path, data, mode } => RenderedEntry::File {
+                path: root.join(path),
+                data,
+                mode,
+            },
+        })
+        .collect();
+    let mut out: Box<dyn Write> = match output == Path::new("-") {
+        true => Box::new(std::io::stdout().lock()),
+        false => Box::new(
+            File::create(output)
+                .context(format!("Failed to create archive: {}", output.display()))?,
+        ),
+    };
+    match format {
+        ArchiveFormat::Tar => write_tar(&mut out, entries)?,
+        ArchiveFormat::TarGz => {
+            let mut encoder = GzEncoder::new(&mut out, Compression::default());
+            write_tar(&mut encoder, entries)?;
+            encoder.finish()?;
+        }
+        // Zip writer needs to seek, which stdout can't do
+        ArchiveFormat::Zip => {
+            let data = write_zip(entries)?;
+            out.write_all(&data)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_tar<W: Write>(out: W, entries: Vec<RenderedEntry>) -> Result<()> {
+    let mtime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let mut tar = Builder::new(out);
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        header.set_mtime(mtime);
+        match entry {
+            RenderedEntry::Directory(path) => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                tar.append_data(&mut header, archive_name(&path), std::io::empty())?;
+            }
+            RenderedEntry::File { path, data, mode } => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_mode(file_mode(mode));
+                header.set_size(data.len() as u64);
+                tar.append_data(&mut header, archive_name(&path), data.as_slice())?;
+            }
+        }
+    }
+    tar.into_inner()?.flush()?;
+    Ok(())
+}
+
+fn write_zip(entries: Vec<RenderedEntry>) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for entry in entries {
+        match entry {
+            RenderedEntry::Directory(path) => {
+                let options = SimpleFileOptions::default().unix_permissions(0o755);
+                zip.add_directory(archive_name(&path), options)?;
+            }
+            RenderedEntry::File { path, data, mode } => {
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .unix_permissions(file_mode(mode));
+                zip.start_file(archive_name(&path), options)?;
+                zip.write_all(&data)?;
+            }
+        }
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+// Archives always use `/`, whatever the platform
+fn archive_name(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn file_mode(mode: u32) -> u32 {
+    match mode & 0o777 {
+        0 => 0o644,
+        mode => mode,
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+use chrono::{Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use std::path::{Path, PathBuf};
+
+use super::{
+    render::{RenderedEntry, Variables},
+    variables::Variable,
+};
+
+// Written into every expanded project, so it can be traced back to the template
+pub const MANIFEST_FILE: &str = ".templater.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub template: String,
+    pub revision: u32,
+    // Of the template archive, tells apart templates recreated elsewhere with the same revision
+    #[serde(default)]
+    pub digest: String,
+    #[serde(default)]
+    pub generated_at: String,
+    // Everything the project was rendered with, so the same files can be rendered again.
+    // Secrets are left out, they have to be given again
+    pub variables: Variables,
+    #[serde(default)]
+    pub commands: Vec<String>,
+}
+
+impl Manifest {
+    pub fn new(
+        template: &str,
+        revision: u32,
+        archive: &Path,
+        variables: &Variables,
+        declared: &[Variable],
+        commands: Vec<String>,
+    ) -> Result<Self> {
+        Ok(Manifest {
+            template: template.to_string(),
+            revision,
+            digest: digest(archive)?,
+            generated_at: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+            variables: without_secrets(variables, declared),
+            commands,
+        })
+    }
+
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        let contents = std::fs::read_to_string(&path).context(format!(
+            "Couldn't read {}, is this a project expanded from a template?",
+            path.display()
+        ))?;
+        serde_json::from_str(&contents).context(format!("Invalid manifest: {}", path.display()))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(MANIFEST_FILE);
+        std::fs::write(&path, self.contents()?)
+            .context(format!("Couldn't write manifest {}", path.display()))
+    }
+
+    // For projects that end up somewhere else than in a directory
+    pub fn entry(&self) -> Result<RenderedEntry> {
+        Ok(RenderedEntry::File {
+            path: PathBuf::from(MANIFEST_FILE),
+            data: self.contents()?.into_bytes(),
+            mode: 0o644,
+        })
+    }
+
+    fn contents(&self) -> Result<String> {
+        let contents = serde_json::to_string_pretty(self).context("Failed to serialize manifest")?;
+        Ok(contents + "\n")
+    }
+}
+
+fn without_secrets(variables: &Variables, declared: &[Variable]) -> Variables {
+    variables
+        .iter()
+        .filter(|(name, _)| {
+            !declared
+                .iter()
+                .any(|variable| variable.secret && variable.name == **name)
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+pub fn digest(archive: &Path) -> Result<String> {
+    let data = std::fs::read(archive)
+        .context(format!("Failed to read archive: {}", archive.display()))?;
+    Ok(format!("{:x}", Sha256::digest(data)))
+}
@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use dialoguer::Select;
+use diffy::{DiffOptions, PatchFormatter};
+use globset::GlobMatcher;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::BTreeMap,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{error::Error, render::RenderedEntry, selection};
+
+// What happens to a template file, when the project already has a different one
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Conflict {
+    // Keep the existing file
+    Skip,
+    Overwrite,
+    // Write template's version next to it, as `<name>.new`
+    Rename,
+    // Add template's version to the end of the existing file
+    Append,
+    // Show the difference and let the user pick one of the above
+    Ask,
+}
+
+const CHOICES: [Conflict; 4] = [Conflict::Skip, Conflict::Overwrite, Conflict::Rename, Conflict::Append];
+
+impl Conflict {
+    fn name(&self) -> &'static str {
+        match self {
+            Conflict::Skip => "skip",
+            Conflict::Overwrite => "overwrite",
+            Conflict::Rename => "rename",
+            Conflict::Append => "append",
+            Conflict::Ask => "ask",
+        }
+    }
+}
+
+// Writes rendered entries into a directory that already has files in it
+pub struct Merger {
+    rules: Vec<(GlobMatcher, Conflict)>,
+    forced: Option<Conflict>,
+}
+
+impl Merger {
+    // A strategy given for the whole run beats the ones the template has for its globs
+    pub fn new(rules: &BTreeMap<String, Conflict>, forced: Option<Conflict>) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|(pattern, conflict)| Ok((selection::glob(pattern)?, *conflict)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Merger { rules, forced })
+    }
+
+    // Conflicts nobody can be asked about are found before anything is written, so they
+    // don't leave the directory half-merged
+    pub fn write_all(&self, dest: &Path, entries: Vec<RenderedEntry>) -> Result<()> {
+        let interactive = std::io::stdin().is_terminal();
+        let mut unresolved = Vec::new();
+        for entry in &entries {
+            let target = dest.join(entry.path());
+            let unresolvable = match entry {
+                RenderedEntry::File { .. } if target.is_dir() => true,
+                RenderedEntry::File { data, .. } if target.is_file() => {
+                    !interactive
+                        && self.strategy(entry.path()) == Conflict::Ask
+                        && std::fs::read(&target)? != *data
+                }
+                RenderedEntry::Directory(_) => target.exists() && !target.is_dir(),
+                _ => false,
+            };
+            if unresolvable {
+                unresolved.push(entry.path().display().to_string());
+            }
+        }
+        if !unresolved.is_empty() {
+            return Err(Error::FileConflict(unresolved).into());
+        }
+
+        for entry in entries {
+            self.write(dest, entry)?;
+        }
+        Ok(())
+    }
+
+    // What writing the entry would do, for previews. `ask` isn't resolved, there is nobody to
+    // ask yet
+    pub fn status(&self, dest: &Path, entry: &RenderedEntry) -> Result<String> {
+        let target = dest.join(entry.path());
+        let status = match entry {
+            RenderedEntry::Directory(_) if target.is_dir() => "exists",
+            RenderedEntry::Directory(_) if target.exists() => "conflict: not a directory",
+            RenderedEntry::File { .. } if target.is_dir() => "conflict: a directory",
+            RenderedEntry::File { data, .. } if target.is_file() => {
+                if std::fs::read(&target)? == *data {
+                    "unchanged"
+                } else {
+                    return Ok(format!("conflict: {}", self.strategy(entry.path()).name()));
+                }
+            }
+            _ => "new",
+        };
+        Ok(status.to_string())
+    }
+
+    fn write(&self, dest: &Path, entry: RenderedEntry) -> Result<()> {
+        let target = dest.join(entry.path());
+        let data = match &entry {
+            RenderedEntry::File { data, .. } if target.exists() => data,
+            _ => return entry.write(dest),
+        };
+        let existing = std::fs::read(&target)
+            .context(format!("Failed to read file: {}", target.display()))?;
+        if existing == *data {
+            return Ok(());
+        }
+
+        let conflict = match self.strategy(entry.path()) {
+            Conflict::Ask => ask(entry.path(), &existing, data)?,
+            conflict => conflict,
+        };
+        match conflict {
+            Conflict::Skip | Conflict::Ask => {
+                log::info!("Keeping existing file: {}", entry.path().display())
+            }
+            Conflict::Overwrite => {
+                log::info!("Overwriting file: {}", entry.path().display());
+                entry.write(dest)?;
+            }
+            Conflict::Rename => {
+                let renamed = free_name(&target);
+                log::info!("Saving template's version as: {}", renamed.display());
+                if let RenderedEntry::File { data, mode, .. } = entry {
+                    let path = renamed.strip_prefix(dest).unwrap_or(&renamed).to_path_buf();
+                    RenderedEntry::File { path, data, mode }.write(dest)?;
+                }
+            }
+            Conflict::Append => {
+                log::info!("Appending to file: {}", entry.path().display());
+                let mut file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&target)
+                    .context(format!("Failed to open file: {}", target.display()))?;
+                if !existing.is_empty() && !existing.ends_with(b"\n") {
+                    file.write_all(b"\n")?;
+                }
+                file.write_all(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn strategy(&self, path: &Path) -> Conflict {
+        if let Some(forced) = self.forced {
+            return forced;
+        }
+        self.rules
+            .iter()
+            .find(|(matcher, _)| matcher.is_match(path))
+            .map_or(Conflict::Ask, |(_, conflict)| *conflict)
+    }
+}
+
+fn ask(path: &Path, existing: &[u8], data: &[u8]) -> Result<Conflict> {
+    log::warn!("{} already exists", path.display());
+    print_diff(path, existing, data);
+    let index = Select::new()
+        .with_prompt("What to do with it?")
+        .items(&CHOICES.map(|conflict| conflict.name()))
+        .default(0)
+        .interact()?;
+    Ok(CHOICES[index])
+}
+
+// Unified diff of two versions of a file, or just a note when they aren't text
+pub fn print_diff(path: &Path, old: &[u8], new: &[u8]) {
+    match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+        (Ok(old), Ok(new)) => {
+            let name = path.display().to_string();
+            let patch = DiffOptions::new()
+                .set_original_filename(format!("a/{}", name))
+                .set_modified_filename(format!("b/{}", name))
+                .create_patch(old, new);
+            let formatter = match std::io::stdout().is_terminal() {
+                true => PatchFormatter::new().with_color(),
+                false => PatchFormatter::new(),
+            };
+            print!("{}", formatter.fmt_patch(&patch));
+        }
+        _ => println!("Binary files a/{0} and b/{0} differ", path.display()),
+    }
+}
+
+// `name.new`, or `name.new.1`, ... when that's taken too
+fn free_name(target: &Path) -> PathBuf {
+    let base = format!("{}.new", target.display());
+    let mut candidate = PathBuf::from(&base);
+    let mut counter = 1;
+    while candidate.exists() {
+        candidate = PathBuf::from(format!("{}.{}", base, counter));
+        counter += 1;
+    }
+    candidate
+}
@@ -1,9 +1,10 @@
-use super::cli::{Command, Task};
+use super::cli::{Command, ExpandArgs, Task};
 
 use anyhow::{Context, Result};
 use chrono::{Local, TimeZone};
+use dialoguer::{Confirm, MultiSelect};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-use globset::{GlobBuilder, GlobMatcher};
+use globset::GlobMatcher;
 use pretty_bytes::converter::convert;
 use prettytable::{Cell, Row, Table};
 use serde::{Deserialize, Serialize};
@@ -12,26 +13,86 @@ use tar::{Archive, Builder};
 use walkdir::WalkDir;
 
 use std::{
-    collections::HashMap, fs::File, io::{Read, Seek, Write}, path::PathBuf, time::SystemTime
+    collections::{BTreeMap, HashMap, HashSet}, fs::File, io::{IsTerminal, Read, Seek, Write}, path::{Path, PathBuf}, time::SystemTime
 };
 
 pub mod error;
 use error::Error;
 
+mod commands;
+use commands::{Hooks, Step};
+
+mod definition;
+use definition::TemplateDefinition;
+
+mod diff;
+
+mod environment;
+
+pub mod export;
+use export::ArchiveFormat;
+
+pub mod merge;
+use merge::{Conflict, Merger};
+
+mod manifest;
+use manifest::Manifest;
+
+mod output;
+use output::CommandLog;
+
+mod render;
+use render::{RenderRules, RenderedEntry, Renderer, Variables};
+
+mod scripts;
+use scripts::{Phase, Script};
+
+mod selection;
+use selection::{Platform, Selection};
+
+mod update;
+
+mod variables;
+use variables::Variable;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Template {
     pub name: String,
     pub description: Option<String>,
-    pub commands: Vec<String>,
+    pub commands: Vec<Step>,
+    #[serde(default)]
+    pub hooks: Hooks,
+    #[serde(default)]
+    pub required_env: Vec<String>,
+    #[serde(default)]
+    pub variables: Vec<Variable>,
+    #[serde(default)]
+    pub conditions: BTreeMap<String, String>,
+    #[serde(default)]
+    pub platforms: BTreeMap<String, Platform>,
+    #[serde(default)]
+    pub render: RenderRules,
+    // Missing in templates stored before files were rendered, those are expanded verbatim
+    #[serde(default)]
+    pub rendered: bool,
+    #[serde(default)]
+    pub conflicts: BTreeMap<String, Conflict>,
+    #[serde(default = "first_revision")]
+    pub revision: u32,
     pub compressed_size: u64,
     pub created: SystemTime,
     pub used: Option<SystemTime>,
 }
 
+fn first_revision() -> u32 {
+    1
+}
+
 pub struct Templater {
     command: Command,
     db: Db,
     storage_path: PathBuf,
+    log: CommandLog,
 }
 
 impl Templater {
@@ -48,6 +109,7 @@ impl Templater {
         let mut templater = Templater {
             command,
             db,
+            log: CommandLog::new(storage_path.join("logs")),
             storage_path,
         };
 
@@ -65,17 +127,19 @@ impl Templater {
                 ignore,
                 definition_file,
                 force,
-            } => self
-                .create_template(path, name, description, commands, ignore, definition_file, *force)
-                .context("Failed to create template"),
-            Task::Expand {
-                name,
-                path,
-                envs,
-                create_as,
-                no_exec,
-            } => self
-                .expand_template(name, path, envs, create_as, no_exec)
+            } => {
+                let args = TemplateDefinition {
+                    name: name.clone(),
+                    description: description.clone(),
+                    commands: commands.iter().cloned().map(Step::from).collect(),
+                    ignore: ignore.clone(),
+                    ..Default::default()
+                };
+                self.create_template(path, args, definition_file, *force)
+                    .context("Failed to create template")
+            }
+            Task::Expand(args) => self
+                .expand_template(args)
                 .context("Failed to expand template"),
             Task::List { name, commands, file_tree } => {
                 if name.is_none() && *commands {
@@ -104,6 +168,18 @@ impl Templater {
                 Ok(())
             }
             Task::Delete { name } => self.delete_template(name),
+            Task::Diff { name, path, values_file, ignore, summary } => self
+                .diff_project(
+                    name,
+                    path.as_deref().unwrap_or(Path::new(".")),
+                    values_file.as_deref(),
+                    ignore,
+                    *summary,
+                )
+                .context("Failed to diff project"),
+            Task::Update { path, reject } => self
+                .update_project(path.as_deref().unwrap_or(Path::new(".")), *reject)
+                .context("Failed to update project"),
             Task::Edit { name } => self.edit_template(name),
         }
     }
@@ -113,6 +189,14 @@ impl Templater {
         if value.is_none() {
             return Err(Error::TemplateNotFound(name.to_string()).into());
         }
+        self.db.open_tree("trust")?.remove(name)?;
+        let history = self.db.open_tree("history")?;
+        for item in history.scan_prefix(format!("{}@", name)) {
+            let (key, value) = item?;
+            let template: Template = serde_json::from_slice(&value)?;
+            history.remove(key)?;
+            let _ = std::fs::remove_file(self.history_path(name, template.revision));
+        }
         if self.command.verbose {
             log::info!("Deleted template metadata: {}", name);
         }
@@ -205,15 +289,115 @@ impl Templater {
             Some(data) => serde_json::from_slice(&data)?,
             None => return Err(Error::TemplateNotFound(name.to_string()).into()),
         };
+        // Only the ones that would run on this platform
+        let scripts = scripts::from_archive(&mut self.open_archive(name)?)?;
 
-        let commands = template
-            .commands
-            .iter()
-            .fold("Commands:".to_string(), |acc, command| {
-                format!("{}\n{}", acc, command)
-            });
-        log::info!("{}", commands);
+        if template.commands.is_empty() && template.hooks.is_empty() && scripts.is_empty() {
+            log::info!("No commands");
+            return Ok(());
+        }
+
+        Self::print_steps(&template);
+        Self::print_scripts(&scripts);
+        Ok(())
+    }
+
+    fn print_steps(template: &Template) {
+        let phases = [
+            ("Pre-create hooks", &template.hooks.pre_create),
+            ("Pre-expand hooks", &template.hooks.pre_expand),
+            ("Commands", &template.commands),
+        ];
+        for (title, steps) in phases {
+            if !steps.is_empty() {
+                log::info!("{}", title);
+                Self::commands_table(steps).printstd();
+            }
+        }
+    }
+
+    fn print_scripts(scripts: &[Script]) {
+        for script in scripts {
+            log::info!("Hook script {} ({})", script, script.phase.name());
+            println!("{}", String::from_utf8_lossy(&script.data).trim_end());
+        }
+    }
+
+    fn commands_table(steps: &[Step]) -> Table {
+        let mut table = Table::new();
+        table.set_titles(Row::new(vec![
+            Cell::new("Command"),
+            Cell::new("Directory"),
+            Cell::new("Environment"),
+            Cell::new("Condition"),
+            Cell::new("Timeout"),
+            Cell::new("On Error"),
+            Cell::new("Platforms"),
+            Cell::new("Optional"),
+        ]));
+
+        for step in steps {
+            let options = &step.options;
+            let env = options
+                .env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<String>>()
+                .join("\n");
+
+            table.add_row(Row::new(vec![
+                Cell::new(&step.command.to_string()),
+                Cell::new(&options.cwd.as_ref().map_or(".".to_string(), |cwd| cwd.display().to_string())),
+                Cell::new(&env),
+                Cell::new(options.when.as_deref().unwrap_or("")),
+                Cell::new(&options.timeout.map_or("None".to_string(), |timeout| format!("{}s", timeout))),
+                Cell::new(if options.continue_on_error { "Continue" } else { "Abort" }),
+                Cell::new(&options.platform.to_string()),
+                Cell::new(match (&options.id, options.optional) {
+                    (Some(id), true) => id,
+                    _ => "No",
+                }),
+            ]));
+        }
+        table
+    }
+
+    // Commands are arbitrary code, so they only run once someone has seen them. Approval is
+    // remembered per template, for this exact command list
+    fn confirm_commands(&self, template: &Template, scripts: &[Script], yes: bool) -> Result<()> {
+        let steps = template.hooks.pre_expand.iter().chain(&template.commands);
+        if (steps.clone().next().is_none() && scripts.is_empty()) || yes {
+            return Ok(());
+        }
 
+        let trusted = self.db.open_tree("trust")?;
+        let digest = commands::digest(steps, scripts)?;
+        if trusted.get(&template.name)?.is_some_and(|approved| *approved == *digest.as_bytes()) {
+            return Ok(());
+        }
+
+        log::warn!("Template {} wants to run these commands:", template.name);
+        if !template.hooks.pre_expand.is_empty() {
+            log::info!("Pre-expand hooks");
+            Self::commands_table(&template.hooks.pre_expand).printstd();
+        }
+        if !template.commands.is_empty() {
+            log::info!("Commands");
+            Self::commands_table(&template.commands).printstd();
+        }
+        Self::print_scripts(scripts);
+        if !std::io::stdin().is_terminal() {
+            return Err(Error::UntrustedCommands(template.name.clone()).into());
+        }
+        let approved = Confirm::new()
+            .with_prompt("Do you trust these commands?")
+            .default(false)
+            .interact()?;
+        if !approved {
+            return Err(Error::UntrustedCommands(template.name.clone()).into());
+        }
+
+        trusted.insert(&template.name, digest.as_bytes())?;
         Ok(())
     }
 
@@ -242,7 +426,7 @@ impl Templater {
             let file_name = path.file_name().unwrap_or_else(|| path.as_os_str()).to_string_lossy();
     
             let def_prefix = &(String::new(), 0);
-            let (prefix, _) = dir_stack.last().unwrap_or(&def_prefix);
+            let (prefix, _) = dir_stack.last().unwrap_or(def_prefix);
             let connector = if i == total_entries - 1 || (depth != last_depth && !is_first_entry) {
                 "└── "
             } else {
@@ -271,42 +455,114 @@ impl Templater {
     }
 
     fn show_file_tree(&self, name: &str) -> Result<()> {
-        let archive_path = self
-            .storage_path
-            .join("archives")
-            .join(format!("{}.tar.gz", name));
-        let archive_file = std::fs::File::open(archive_path)?;
-
-        let decoder = GzDecoder::new(archive_file);
-        let mut archive = Archive::new(decoder);
+        let mut archive = self.open_archive(name)?;
 
         log::info!("File Tree");
         Self::print_tar_tree(&mut archive)
     }
 
-    fn expand_template(
+    fn open_archive(&self, name: &str) -> Result<Archive<GzDecoder<File>>> {
+        Self::open_archive_at(&self.archive_path(name))
+    }
+
+    fn open_archive_at(archive_path: &Path) -> Result<Archive<GzDecoder<File>>> {
+        let archive_file = File::open(archive_path)
+            .context(format!("Failed to open archive: {}", archive_path.display()))?;
+        Ok(Archive::new(GzDecoder::new(archive_file)))
+    }
+
+    fn archive_path(&self, name: &str) -> PathBuf {
+        self.storage_path
+            .join("archives")
+            .join(format!("{}.tar.gz", name))
+    }
+
+    // Earlier revisions are kept around, so projects expanded from them can be updated
+    fn history_path(&self, name: &str, revision: u32) -> PathBuf {
+        self.storage_path
+            .join("archives")
+            .join("history")
+            .join(format!("{}-{}.tar.gz", name, revision))
+    }
+
+    fn history_key(name: &str, revision: u32) -> String {
+        format!("{}@{}", name, revision)
+    }
+
+    // Entries of the template that make it into projects, given these variables
+    fn selection(&self, template: &Template, renderer: &Renderer) -> Result<Selection> {
+        let mut selection = Selection::default();
+        selection.exclude(scripts::RESERVED_DIR)?;
+        for (pattern, condition) in &template.conditions {
+            if !renderer.evaluate(condition)? {
+                if self.command.verbose {
+                    log::info!("Skipping {}, condition not met: {}", pattern, condition);
+                }
+                selection.exclude(pattern)?;
+            }
+        }
+        for (pattern, platform) in &template.platforms {
+            if !platform.is_current() {
+                if self.command.verbose {
+                    log::info!("Skipping {}, only expanded on: {}", pattern, platform);
+                }
+                selection.exclude(pattern)?;
+            }
+        }
+        Ok(selection)
+    }
+
+    fn renderer(template: &Template, variables: Variables) -> Result<Renderer> {
+        let renderer = Renderer::new(variables, &template.render)?;
+        Ok(match template.rendered {
+            true => renderer,
+            false => renderer.verbatim(),
+        })
+    }
+
+    // Renders files of the template in memory, like they would be written by expand
+    fn render_files(
         &self,
-        name: &str,
-        path: &Option<PathBuf>,
-        envs: &Vec<String>,
-        create_as: &Option<String>,
-        no_exec: &bool,
-    ) -> Result<()> {
+        template: &Template,
+        archive_path: &Path,
+        variables: Variables,
+    ) -> Result<BTreeMap<PathBuf, (Vec<u8>, u32)>> {
+        let renderer = Self::renderer(template, variables)?;
+        let mut selection = self.selection(template, &renderer)?;
+        let mut files = BTreeMap::new();
+        renderer.render_archive(
+            &mut Self::open_archive_at(archive_path)?,
+            &mut selection,
+            |entry| {
+                if let RenderedEntry::File { path, data, mode } = entry {
+                    files.insert(path, (data, mode));
+                }
+                Ok(())
+            },
+        )?;
+        Ok(files)
+    }
+
+    fn expand_template(&self, args: &ExpandArgs) -> Result<()> {
+        let name = &args.name;
         let mut template: Template = match self.db.get(name)? {
             Some(data) => serde_json::from_slice(&data)?,
             None => return Err(Error::TemplateNotFound(name.to_string()).into()),
         };
 
-        template.used = Some(SystemTime::now());
-        self.db.insert(name, serde_json::to_vec(&template)?)?;
+        if !args.dry_run {
+            template.used = Some(SystemTime::now());
+            self.db.insert(name, serde_json::to_vec(&template)?)?;
+        }
         let template = template; // unmut
+        self.log.start(&template.name);
 
-        let path = match path {
+        let path = match &args.path {
             Some(path) => path.clone(),
             None => PathBuf::from("."),
         };
 
-        let create_as = match create_as {
+        let create_as = match &args.create_as {
             Some(create_as) => create_as.clone(),
             None => name.to_string(),
         };
@@ -315,217 +571,627 @@ impl Templater {
             log::info!("Expanding template {} to {}", name, path.display());
         }
 
-        let archive_path = self
-            .storage_path
-            .join("archives")
-            .join(format!("{}.tar.gz", name));
-        let archive = File::open(&archive_path)?;
-        let dec = GzDecoder::new(archive);
-        let mut archive = Archive::new(dec);
+        let mut archive = self.open_archive(name)?;
+
+        // Adding to an existing directory, or creating a new one
+        let new_path = match &args.into {
+            Some(into) if into.is_dir() => into
+                .canonicalize()
+                .context(format!("Failed to resolve path: {}", into.display()))?,
+            Some(into) => return Err(Error::InvalidTemplateDir(into.clone()).into()),
+            // Nothing is written there, it only names the project
+            None if args.output_archive.is_some() => path.join(&create_as),
+            None if path.join(&create_as).exists() => {
+                return Err(Error::InvalidTemplateDir(path.join(&create_as)).into())
+            }
+            None => path.join(&create_as),
+        };
 
-        let new_path = path.join(&create_as);
-        if new_path.exists() {
-            return Err(Error::InvalidTemplateDir(new_path).into());
+        // Commands expect the whole project, so they aren't run for a part of it - or when
+        // there is no directory to run them in
+        let partial = !args.only.is_empty() || !args.exclude.is_empty();
+        if partial && !args.no_exec && self.command.verbose {
+            log::info!("Expanding only a part of the template, commands won't be run");
         }
+        let no_exec = args.no_exec || partial || args.output_archive.is_some();
+        let format = match (&args.output_archive, args.archive_format) {
+            (Some(_), Some(format)) => Some(format),
+            (Some(output), None) => Some(ArchiveFormat::detect(output)?),
+            (None, _) => None,
+        };
 
-        std::fs::create_dir_all(&new_path)?;
-        if self.command.verbose {
-            log::info!("Creating directory: {}", new_path.display());
+        // Hook scripts are needed before anything gets unpacked, so they get a pass of their own
+        let mut scripts = match no_exec {
+            true => Vec::new(),
+            false => scripts::from_archive(&mut self.open_archive(name)?)?,
+        };
+        scripts.retain(|script| script.phase != Phase::PreCreate);
+        if !no_exec && !args.dry_run {
+            self.confirm_commands(&template, &scripts, args.yes)?;
         }
-        archive.unpack(&new_path)?;
-        if self.command.verbose {
-            log::info!("Unpacked archive: {}", archive_path.display());
+
+        // Later entries win, so --env overrides env files
+        let mut envs = HashMap::new();
+        for env_file in &args.env_files {
+            envs.extend(environment::load_file(env_file)?);
+        }
+        for env in &args.envs {
+            let (key, value) = environment::parse(env)?;
+            envs.insert(key, value);
         }
 
-        if *no_exec {
-            return Ok(());
+        if !no_exec {
+            let missing = environment::missing(&template.required_env, &envs);
+            if !missing.is_empty() {
+                return Err(Error::MissingEnv(missing).into());
+            }
         }
 
-        let cwd = std::env::current_dir()?;
+        let mut supplied = match &args.values_file {
+            Some(values_file) => variables::load_answers(values_file)?,
+            None => Variables::new(),
+        };
+        for (key, value) in &envs {
+            supplied.insert(key.clone(), value.clone().into());
+        }
 
-        std::env::set_current_dir(&new_path)?;
-        for command in template.commands {
-            let mut parts = command.split_whitespace();
-            let command = parts.next().unwrap();
-            let args = parts.collect::<Vec<&str>>();
+        let interactive = args.values_file.is_none() && std::io::stdin().is_terminal();
+        let mut answers = variables::resolve(&template.variables, &supplied, interactive)?;
+        for (key, value) in supplied {
+            answers.entry(key).or_insert(value);
+        }
+        let builtins = variables::builtins(&template.name, template.revision, &new_path);
+
+        // Environment often holds secrets, so values given with it are only recorded in the
+        // manifest when they are for declared variables, or override built-in ones
+        let unrecorded = envs
+            .keys()
+            .filter(|name| {
+                !builtins.contains_key(*name)
+                    && !template.variables.iter().any(|variable| variable.name == **name)
+            })
+            .cloned()
+            .collect::<HashSet<String>>();
 
+        // Values files get passed around even more than projects, so they leave out the same
+        // values the manifest does, and secrets - those have to be given again
+        if let Some(save_values) = args.save_values.as_ref().filter(|_| !args.dry_run) {
+            let saved = answers
+                .iter()
+                .filter(|(name, _)| {
+                    !unrecorded.contains(*name)
+                        && !template
+                            .variables
+                            .iter()
+                            .any(|variable| variable.secret && variable.name == **name)
+                })
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect::<Variables>();
+            variables::save_answers(save_values, &saved)?;
             if self.command.verbose {
-                log::info!("Running command: {} {}", command, args.join(" "));
+                log::info!("Saved values to: {}", save_values.display());
             }
+        }
+
+        let mut variables = builtins.clone();
+        variables.extend(answers);
+
+        // Commands get built-in values too, with whatever overrides were given
+        envs.extend(variables::exports(&builtins, &variables));
+
+        let mut renderer = Self::renderer(&template, variables)?;
+        // Hook scripts aren't run, so values they'd set are only shown as placeholders
+        if args.dry_run && !scripts.is_empty() {
+            renderer = renderer.placeholder("<set by hook>");
+        }
+        if !template.rendered && self.command.verbose {
+            log::info!("Template was created by an older version of templater, files are copied as they are");
+        }
 
-            let envs: HashMap<String, String> = envs
+        let mut selection = self.selection(&template, &renderer)?;
+        for pattern in &args.only {
+            selection.include(pattern)?;
+        }
+        for pattern in &args.exclude {
+            selection.exclude(pattern)?;
+        }
+
+        let chosen = match no_exec {
+            true => HashSet::new(),
+            false => Self::choose_steps(&template, args, interactive)?,
+        };
+        let wanted = |steps: &[Step]| -> Vec<Step> {
+            steps
                 .iter()
-                .map(|env| {
-                    let mut parts = env.split("=");
-                    let key = parts.next().unwrap();
-                    let value = parts.collect::<Vec<&str>>().join("=");
-                    (key.to_string(), value)
+                .filter(|step| {
+                    !step.options.optional
+                        || step.options.id.as_ref().is_some_and(|id| chosen.contains(id))
                 })
-                .collect();
-
-            let status = if cfg!(target_os = "windows") {
-                std::process::Command::new("cmd")
-                    .arg("/C")
-                    .arg(command)
-                    .args(args)
-                    .envs(envs.iter())
-                    .status()?
-            } else {
-                std::process::Command::new("sh")
-                    .arg("-c")
-                    .arg(format!("{} {}", command, args.join(" ")))
-                    .envs(envs.iter())
-                    .status()?
+                .cloned()
+                .collect()
+        };
+        let (pre_expand, post_expand) = match no_exec {
+            true => (Vec::new(), Vec::new()),
+            false => (wanted(&template.hooks.pre_expand), wanted(&template.commands)),
+        };
+
+        if args.dry_run {
+            let mut entries = Vec::new();
+            renderer.render_archive(&mut archive, &mut selection, |entry| {
+                entries.push(entry);
+                Ok(())
+            })?;
+            let mut steps = Vec::new();
+            for (phase, hook) in [(Phase::PreExpand, &pre_expand), (Phase::PostExpand, &post_expand)] {
+                for script in scripts::for_phase(&scripts, phase) {
+                    let mut env: BTreeMap<String, String> = envs.clone().into_iter().collect();
+                    env.extend(variables::exports(renderer.variables(), renderer.variables()));
+                    steps.push((phase, script.to_string(), new_path.clone(), env));
+                }
+                for (step, cwd) in self.plan_steps(hook, &renderer, &new_path)? {
+                    let mut env: BTreeMap<String, String> = envs.clone().into_iter().collect();
+                    env.extend(step.options.env.clone());
+                    steps.push((phase, step.command.to_string(), cwd, env));
+                }
+            }
+            // Merged files may be left alone, so each one gets what would happen to it
+            let statuses = match &args.into {
+                Some(_) => {
+                    let merger = Merger::new(&template.conflicts, args.on_conflict)?;
+                    let statuses = entries
+                        .iter()
+                        .map(|entry| merger.status(&new_path, entry))
+                        .collect::<Result<Vec<String>>>()?;
+                    Some(statuses)
+                }
+                None => None,
             };
+            Self::print_dry_run(&renderer, &new_path, &entries, statuses.as_deref(), &steps);
+            return Ok(());
+        }
+
+        if let (Some(output), Some(format)) = (&args.output_archive, format) {
+            let mut entries = Vec::new();
+            renderer.render_archive(&mut archive, &mut selection, |entry| {
+                entries.push(entry);
+                Ok(())
+            })?;
+            if !args.no_manifest && !partial {
+                let phases = [(Phase::PreExpand, &pre_expand), (Phase::PostExpand, &post_expand)];
+                entries.push(
+                    self.manifest(&template, &renderer, &unrecorded, phases, &scripts, &new_path)?
+                        .entry()?,
+                );
+            }
+            export::write(output, format, &create_as, entries)?;
+            if self.command.verbose {
+                log::info!("Wrote project to archive: {}", output.display());
+            }
+            return Ok(());
+        }
 
-            if !status.success() {
-                return Err(Error::CreateTemplate(command.to_string()).into());
+        // Everything happens in a staging directory next to the target, which is only renamed
+        // into place once the project is complete. Dropping it on error is the rollback.
+        // There is no undoing a merge though, so with --into files go straight to their place
+        let (staging, merger) = match &args.into {
+            Some(_) => (None, Some(Merger::new(&template.conflicts, args.on_conflict)?)),
+            None => {
+                // Next to the project itself, which `--as` may put in a subdirectory of `--path`
+                let parent = new_path.parent().unwrap_or(Path::new("."));
+                let file_name = new_path.file_name().unwrap_or_default().to_string_lossy();
+                std::fs::create_dir_all(parent)?;
+                let staging = tempfile::Builder::new()
+                    .prefix(&format!(".{}-", file_name))
+                    .tempdir_in(parent)
+                    .context("Failed to create staging directory")?;
+                if self.command.verbose {
+                    log::info!("Created staging directory: {}", staging.path().display());
+                }
+                (Some(staging), None)
             }
+        };
+        let project_dir = match &staging {
+            Some(staging) => staging.path().to_path_buf(),
+            None => new_path.clone(),
+        };
+
+        let result = self
+            .run_phase(
+                &pre_expand,
+                &scripts::for_phase(&scripts, Phase::PreExpand),
+                &template.variables,
+                &mut renderer,
+                &project_dir,
+                &mut envs,
+            )
+            .and_then(|_| match &merger {
+                Some(merger) => {
+                    let mut entries = Vec::new();
+                    renderer.render_archive(&mut archive, &mut selection, |entry| {
+                        entries.push(entry);
+                        Ok(())
+                    })?;
+                    merger.write_all(&project_dir, entries)
+                }
+                None => renderer.render_archive(&mut archive, &mut selection, |entry| {
+                    entry.write(&project_dir)
+                }),
+            })
+            .and_then(|_| {
+                if self.command.verbose {
+                    log::info!("Unpacked template: {}", name);
+                }
+                self.run_phase(
+                    &post_expand,
+                    &scripts::for_phase(&scripts, Phase::PostExpand),
+                    &template.variables,
+                    &mut renderer,
+                    &project_dir,
+                    &mut envs,
+                )
+            })
+            .and_then(|_| {
+                if args.no_manifest || partial {
+                    return Ok(());
+                }
+                // Project can only be traced back to one template, so a manifest of another one
+                // is kept, and updates keep coming from it
+                if merger.is_some() && project_dir.join(manifest::MANIFEST_FILE).exists() {
+                    match Manifest::load(&project_dir) {
+                        Ok(existing) if existing.template == template.name => {}
+                        Ok(existing) => {
+                            log::warn!(
+                                "Kept manifest of template {}, project won't be updated from {}",
+                                existing.template,
+                                template.name
+                            );
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            log::warn!("Kept existing manifest: {:#}", e);
+                            return Ok(());
+                        }
+                    }
+                }
+                self.manifest(
+                    &template,
+                    &renderer,
+                    &unrecorded,
+                    [(Phase::PreExpand, &pre_expand), (Phase::PostExpand, &post_expand)],
+                    &scripts,
+                    &project_dir,
+                )?
+                .save(&project_dir)?;
+                if self.command.verbose {
+                    log::info!("Wrote manifest: {}", manifest::MANIFEST_FILE);
+                }
+                Ok(())
+            });
+
+        let staging = match staging {
+            Some(staging) => staging,
+            None => return result,
+        };
+        if let Err(e) = result {
+            if args.keep_on_failure {
+                let kept = staging.keep();
+                log::warn!("Keeping unfinished project in: {}", kept.display());
+            }
+            return Err(e);
+        }
+
+        std::fs::rename(staging.path(), &new_path)
+            .context(format!("Failed to move project to {}", new_path.display()))?;
+        let _ = staging.keep(); // already moved, nothing left to clean up
+        if self.command.verbose {
+            log::info!("Moved project to: {}", new_path.display());
         }
-        std::env::set_current_dir(&cwd)?;
 
         Ok(())
     }
 
-    fn create_template(
+    // Records where the project came from, and what was run to make it
+    fn manifest(
         &self,
-        path: &PathBuf,
-        name: &Option<String>,
-        description: &Option<String>,
-        commands: &Vec<String>,
-        ignore: &Vec<String>,
-        definition: &Option<PathBuf>,
-        force: bool,
-    ) -> Result<()> {
-        if !path.exists() || !path.is_dir() {
-            return Err(Error::InvalidTemplateDir(path.clone()).into());
+        template: &Template,
+        renderer: &Renderer,
+        unrecorded: &HashSet<String>,
+        phases: [(Phase, &Vec<Step>); 2],
+        scripts: &[Script],
+        project_dir: &Path,
+    ) -> Result<Manifest> {
+        let mut commands = Vec::new();
+        for (phase, steps) in phases {
+            for script in scripts::for_phase(scripts, phase) {
+                commands.push(script.to_string());
+            }
+            for (step, _) in self.plan_steps(steps, renderer, project_dir)? {
+                commands.push(step.command.to_string());
+            }
         }
+        let mut variables = renderer.variables().clone();
+        variables.retain(|name, _| !unrecorded.contains(name));
+
+        Manifest::new(
+            &template.name,
+            template.revision,
+            &self.archive_path(&template.name),
+            &variables,
+            &template.variables,
+            commands,
+        )
+    }
 
-        /* The most discusting code I've ever written is here */
+    // Ids of optional steps to run - given on the command line, or picked from a list
+    fn choose_steps(
+        template: &Template,
+        args: &ExpandArgs,
+        interactive: bool,
+    ) -> Result<HashSet<String>> {
+        let optional = template
+            .hooks
+            .pre_expand
+            .iter()
+            .chain(&template.commands)
+            .filter(|step| step.options.optional)
+            .collect::<Vec<&Step>>();
+        let ids = optional
+            .iter()
+            .filter_map(|step| step.options.id.clone())
+            .collect::<HashSet<String>>();
 
-        // definition parsing
-        #[derive(Serialize, Deserialize)]
-        struct TemplateDefinition {
-            name: Option<String>,
-            description: Option<String>,
-            commands: Vec<String>,
-            ignore: Vec<String>
+        if args.all_steps {
+            return Ok(ids);
+        }
+        if !args.steps.is_empty() {
+            if let Some(unknown) = args.steps.iter().find(|id| !ids.contains(*id)) {
+                return Err(Error::InvalidArgument(format!("Unknown optional step: {}", unknown)).into());
+            }
+            return Ok(args.steps.iter().cloned().collect());
+        }
+        if optional.is_empty() || !interactive {
+            return Ok(HashSet::new());
         }
 
-        impl TemplateDefinition {
-            fn load_template_definition(path: &PathBuf) -> Option<Self> {
-                let def_file = std::fs::File::open(path);
-                match def_file {
-                    Err(e) => {
-                        log::error!("Couldn't open definition file {}: {e}", path.display());
-                        return None;
-                    }
-                    Ok(mut f) => {
-                        let mut contents = String::new();
-                        if let Err(e) = f.read_to_string(&mut contents) {
-                            log::error!("Couldn't read from definition file: {e}");
-                            return None;
-                        }
+        let items = optional
+            .iter()
+            .map(|step| match &step.options.prompt {
+                Some(prompt) => prompt.clone(),
+                None => step.command.to_string(),
+            })
+            .collect::<Vec<String>>();
+        let picked = MultiSelect::new()
+            .with_prompt("Optional steps to run")
+            .items(&items)
+            .interact()?;
+        Ok(picked
+            .into_iter()
+            .filter_map(|index| optional[index].options.id.clone())
+            .collect())
+    }
 
-                        match serde_json::from_str(&contents) {
-                            Ok(template_def) => Some(template_def),
-                            Err(e) => {
-                                eprintln!("Provided file is not valid definition file: {e}");
-                                None
-                            }
-                        }
+    // Steps that will run, with the directories they run in
+    fn plan_steps<'a>(
+        &self,
+        steps: &'a [Step],
+        renderer: &Renderer,
+        project_dir: &Path,
+    ) -> Result<Vec<(&'a Step, PathBuf)>> {
+        let mut planned = Vec::new();
+        for step in steps {
+            if !step.options.platform.is_current() {
+                if self.command.verbose {
+                    log::info!(
+                        "Skipping command, only run on {}: {}",
+                        step.options.platform,
+                        step.command
+                    );
+                }
+                continue;
+            }
+            if let Some(when) = &step.options.when {
+                if !renderer.evaluate(when)? {
+                    if self.command.verbose {
+                        log::info!("Skipping command, condition not met: {}", step.command);
                     }
+                    continue;
                 }
             }
+
+            let cwd = match &step.options.cwd {
+                Some(cwd) => render::safe_join(project_dir, &renderer.render_path(cwd)?)?,
+                None => project_dir.to_path_buf(),
+            };
+            planned.push((step, cwd));
         }
+        Ok(planned)
+    }
 
-        let definition_contents = {
-            match definition {
-                None => {None},
-                Some(d) => {
-                    TemplateDefinition::load_template_definition(d)
-                }
+    // Hook scripts of a phase go first, so the variables they set are seen by its steps
+    fn run_phase(
+        &self,
+        steps: &[Step],
+        scripts: &[&Script],
+        declared: &[Variable],
+        renderer: &mut Renderer,
+        dir: &Path,
+        envs: &mut HashMap<String, String>,
+    ) -> Result<()> {
+        for script in scripts {
+            if self.command.verbose {
+                log::info!("Running hook script: {}", script);
             }
-        };
-
-        let config = match definition_contents {
-            Some(d) => {
-                let name = match name {
-                    Some(n) => n.clone(),
-                    None => {
-                        match d.name {
-                            Some(n) => n.clone(),
-                            None => {
-                                path
-                                .file_name()
-                                .context("Failed to get file name")?
-                                .to_string_lossy()
-                                .to_string()
-                            }
-                        }
-                    }
-                };
+            let mut script_envs = envs.clone();
+            script_envs.extend(variables::exports(renderer.variables(), renderer.variables()));
 
-                let description = match description {
-                    Some(desc) => Some(desc.clone()),
-                    None => {
-                        match d.description {
-                            Some(desc) => Some(desc.clone()),
-                            None => None
-                        }
-                    }
+            for (name, value) in script.run(dir, &script_envs, &self.log)? {
+                let value = match declared.iter().find(|variable| variable.name == name) {
+                    Some(variable) => variable.validate(&value)?,
+                    None => value,
                 };
+                if self.command.verbose {
+                    log::info!("Hook script set {} = {}", name, variables::text(&value));
+                }
+                envs.insert(format!("TEMPLATER_{}", name.to_uppercase()), variables::text(&value));
+                renderer.set(name, value);
+            }
+        }
+        self.run_steps(steps, renderer, dir, envs)
+    }
 
-                let commands = {
-                    if commands.len() == 0 && d.commands.len() != 0 {
-                        d.commands
-                    } else {
-                        commands.to_vec()
-                    }
-                };
+    fn run_steps(
+        &self,
+        steps: &[Step],
+        renderer: &Renderer,
+        project_dir: &Path,
+        envs: &HashMap<String, String>,
+    ) -> Result<()> {
+        for (step, cwd) in self.plan_steps(steps, renderer, project_dir)? {
+            if self.command.verbose {
+                log::info!("Running command: {} (in {})", step.command, cwd.display());
+            }
 
-                let ignore = {
-                    if ignore.len() == 0 && d.ignore.len() != 0 {
-                        d.ignore
-                    } else {
-                        ignore.to_vec()
-                    }
-                };
+            let result = step.run(&cwd, envs, &self.log).and_then(|status| {
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(self.log.failure(Error::CreateTemplate(step.command.to_string())))
+                }
+            });
 
-                TemplateDefinition {
-                    name: Some(name),
-                    description,
-                    commands,
-                    ignore,
+            match result {
+                Err(e) if step.options.continue_on_error => {
+                    log::warn!("{}, continuing", e);
                 }
-            },
-            None => {
-                let name = match name {
-                    Some(n) => n.clone(),
-                    None => {
-                        path
-                        .file_name()
-                        .context("Failed to get file name")?
-                        .to_string_lossy()
-                        .to_string()
-                    }
-                };
+                result => result?,
+            }
+        }
 
-                TemplateDefinition {
-                    name: Some(name),
-                    description: description.clone(),
-                    commands: commands.clone(),
-                    ignore: ignore.clone(),
+        Ok(())
+    }
+
+    fn print_dry_run(
+        renderer: &Renderer,
+        project_dir: &Path,
+        entries: &[RenderedEntry],
+        statuses: Option<&[String]>,
+        steps: &[(Phase, String, PathBuf, BTreeMap<String, String>)],
+    ) {
+        let mut table = Table::new();
+        table.set_titles(Row::new(vec![Cell::new("Variable"), Cell::new("Value")]));
+        for (name, value) in renderer.variables() {
+            table.add_row(Row::new(vec![Cell::new(name), Cell::new(&variables::text(value))]));
+        }
+        log::info!("Variables");
+        table.printstd();
+
+        let mut table = Table::new();
+        let mut titles = vec![Cell::new("Path"), Cell::new("Size")];
+        if statuses.is_some() {
+            titles.push(Cell::new("Status"));
+        }
+        table.set_titles(Row::new(titles));
+        for (i, entry) in entries.iter().enumerate() {
+            let path = project_dir.join(entry.path()).display().to_string();
+            let mut row = match entry {
+                RenderedEntry::Directory(_) => vec![Cell::new(&format!("{}/", path)), Cell::new("")],
+                RenderedEntry::File { data, .. } => {
+                    vec![Cell::new(&path), Cell::new(&convert(data.len() as f64))]
                 }
+            };
+            if let Some(statuses) = statuses {
+                row.push(Cell::new(&statuses[i]));
             }
+            table.add_row(Row::new(row));
+        }
+        log::info!("Files");
+        table.printstd();
+
+        if steps.is_empty() {
+            log::info!("No commands");
+            return;
+        }
+
+        let mut table = Table::new();
+        table.set_titles(Row::new(vec![
+            Cell::new("Phase"),
+            Cell::new("Command"),
+            Cell::new("Directory"),
+            Cell::new("Environment"),
+        ]));
+        for (phase, command, cwd, env) in steps {
+            let env = env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<String>>()
+                .join("\n");
+
+            table.add_row(Row::new(vec![
+                Cell::new(phase.name()),
+                Cell::new(command),
+                Cell::new(&cwd.display().to_string()),
+                Cell::new(&env),
+            ]));
+        }
+        log::info!("Commands");
+        table.printstd();
+    }
+
+    fn create_template(
+        &self,
+        path: &PathBuf,
+        args: TemplateDefinition,
+        definition: &Option<PathBuf>,
+        force: bool,
+    ) -> Result<()> {
+        if !path.exists() || !path.is_dir() {
+            return Err(Error::InvalidTemplateDir(path.clone()).into());
+        }
+
+        let config = match definition {
+            Some(definition) => args.merge(TemplateDefinition::load(definition)?),
+            None => args,
         };
 
-        /* End of very very bad code */
+        config.check()?;
 
-        let name = config.name.unwrap();
+        let name = match config.name {
+            Some(name) => name,
+            None => path
+                .file_name()
+                .context("Failed to get file name")?
+                .to_string_lossy()
+                .to_string(),
+        };
+
+        let previous = match self.db.get(&name)? {
+            Some(_) if !force => return Err(Error::TemplateExists(name).into()),
+            Some(data) => Some(serde_json::from_slice::<Template>(&data)?),
+            None => None,
+        };
+        let revision = previous
+            .as_ref()
+            .map_or(first_revision(), |previous| previous.revision + 1);
+
+        self.log.start(&name);
+        let scripts = scripts::from_dir(path)?;
+        let pre_create = scripts::for_phase(&scripts, Phase::PreCreate);
+        if !config.hooks.pre_create.is_empty() || !pre_create.is_empty() {
+            let builtins = variables::builtins(&name, revision, path);
+            let mut envs = variables::exports(&builtins, &builtins);
+            let mut renderer = Renderer::new(builtins, &config.render)?;
+            self.run_phase(
+                &config.hooks.pre_create,
+                &pre_create,
+                &[],
+                &mut renderer,
+                path,
+                &mut envs,
+            )?;
+        }
 
-        if self.db.contains_key(&name)? && !force {
-            return Err(Error::TemplateExists(name).into());
+        if let Some(previous) = &previous {
+            self.keep_revision(previous)?;
         }
 
         if self.command.verbose {
@@ -536,7 +1202,7 @@ impl Templater {
             .storage_path
             .join("archives")
             .join(format!("{}.tar.gz", name));
-        std::fs::create_dir_all(&archive_path.parent().unwrap())?;
+        std::fs::create_dir_all(archive_path.parent().unwrap())?;
         if self.command.verbose {
             log::info!(
                 "Creted archive directory: {}",
@@ -552,20 +1218,14 @@ impl Templater {
         let enc = GzEncoder::new(tarball, Compression::default());
         let mut tar = Builder::new(enc);
 
-        let ignore_list = config.ignore
+        let ignore_list = config
+            .ignore
             .iter()
-            .map(|pattern| {
-                let mut builder = GlobBuilder::new(pattern);
-                builder.case_insensitive(true);
-                builder
-                    .build()
-                    .context(format!("Failed to build glob pattern: {}", pattern))
-                    .map(|glob| glob.compile_matcher())
-            })
+            .map(|pattern| selection::glob(pattern))
             .collect::<Result<Vec<GlobMatcher>>>()?;
 
         if self.command.verbose {
-            log::info!("Filtering files with ignore patterns: {:?}", ignore);
+            log::info!("Filtering files with ignore patterns: {:?}", config.ignore);
         }
 
         let file_path_list = WalkDir::new(path)
@@ -611,6 +1271,15 @@ impl Templater {
             name: name.clone(),
             description: config.description.clone(),
             commands: config.commands.clone(),
+            hooks: config.hooks.clone(),
+            required_env: config.required_env.clone(),
+            variables: config.variables.clone(),
+            conditions: config.conditions.clone(),
+            platforms: config.platforms.clone(),
+            render: config.render.clone(),
+            rendered: true,
+            conflicts: config.conflicts.clone(),
+            revision,
             compressed_size,
             created: SystemTime::now(),
             used: None,
@@ -630,6 +1299,21 @@ impl Templater {
         Ok(())
     }
 
+    fn keep_revision(&self, template: &Template) -> Result<()> {
+        let history_path = self.history_path(&template.name, template.revision);
+        std::fs::create_dir_all(history_path.parent().unwrap())?;
+        std::fs::copy(self.archive_path(&template.name), &history_path)
+            .context(format!("Failed to keep archive of revision {}", template.revision))?;
+        self.db.open_tree("history")?.insert(
+            Self::history_key(&template.name, template.revision),
+            serde_json::to_vec(template)?,
+        )?;
+        if self.command.verbose {
+            log::info!("Kept revision {} in: {}", template.revision, history_path.display());
+        }
+        Ok(())
+    }
+
     fn edit_template(&self, name: &str) -> Result<()> {
         let template: Template = match self.db.get(name)? {
             Some(data) => serde_json::from_slice(&data)?,
@@ -639,17 +1323,18 @@ impl Templater {
         let editor = std::env::var("EDITOR").unwrap_or("vi".to_string());
         let mut file = tempfile::NamedTempFile::new()?;
 
-        #[derive(Serialize, Deserialize)]
-        struct TemplateEditFile {
-            name: String,
-            description: Option<String>,
-            commands: Vec<String>,
-        }
-
-        let template_edit_file = TemplateEditFile {
-            name: template.name.clone(),
+        let template_edit_file = TemplateDefinition {
+            name: Some(template.name.clone()),
             description: template.description.clone(),
             commands: template.commands.clone(),
+            hooks: template.hooks.clone(),
+            required_env: template.required_env.clone(),
+            ignore: Vec::new(),
+            variables: template.variables.clone(),
+            conditions: template.conditions.clone(),
+            platforms: template.platforms.clone(),
+            render: template.render.clone(),
+            conflicts: template.conflicts.clone(),
         };
 
         file.write_all(serde_json::to_string_pretty(&template_edit_file)?.as_bytes())?;
@@ -666,11 +1351,21 @@ impl Templater {
 
         file.read_to_string(&mut contents)?;
 
-        let template_edit: TemplateEditFile = serde_json::from_str(&contents)?;
+        let template_edit: TemplateDefinition = serde_json::from_str(&contents)?;
+        template_edit.check()?;
         let template = Template {
-            name: template_edit.name,
+            name: template_edit.name.unwrap_or(template.name),
             description: template_edit.description,
             commands: template_edit.commands,
+            hooks: template_edit.hooks,
+            required_env: template_edit.required_env,
+            variables: template_edit.variables,
+            conditions: template_edit.conditions,
+            platforms: template_edit.platforms,
+            render: template_edit.render,
+            rendered: template.rendered,
+            conflicts: template_edit.conflicts,
+            revision: template.revision,
             compressed_size: template.compressed_size,
             created: template.created,
             used: template.used,
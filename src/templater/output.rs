@@ -0,0 +1,116 @@
+use anyhow::{Context, Result};
+use chrono::Local;
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use super::error::Error;
+
+// How much of the output of a failed command ends up in the error
+const TAIL_LINES: usize = 20;
+
+// Output of every command run for one template goes into a single file in `dir`, which is
+// only created once there is something to write
+pub struct CommandLog {
+    dir: PathBuf,
+    state: Arc<Mutex<LogState>>,
+}
+
+#[derive(Default)]
+struct LogState {
+    name: String,
+    path: Option<PathBuf>,
+    file: Option<File>,
+    tail: VecDeque<String>,
+}
+
+impl LogState {
+    fn record(&mut self, line: &str) {
+        if let Some(file) = &mut self.file {
+            let _ = writeln!(file, "{}", line);
+        }
+        if self.tail.len() == TAIL_LINES {
+            self.tail.pop_front();
+        }
+        self.tail.push_back(line.to_string());
+    }
+}
+
+impl CommandLog {
+    pub fn new(dir: PathBuf) -> Self {
+        CommandLog {
+            dir,
+            state: Arc::new(Mutex::new(LogState::default())),
+        }
+    }
+
+    // Names the file after the template commands are about to be run for
+    pub fn start(&self, name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.name = name.to_string();
+        state.path = None;
+        state.file = None;
+    }
+
+    pub fn begin(&self, command: &str, cwd: &Path) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.file.is_none() {
+            std::fs::create_dir_all(&self.dir)?;
+            let timestamp = Local::now().format("%Y%m%d-%H%M%S");
+            let path = self.dir.join(format!("{}-{}.log", state.name, timestamp));
+            let file = File::create(&path)
+                .context(format!("Failed to create log file: {}", path.display()))?;
+            state.path = Some(path);
+            state.file = Some(file);
+        }
+        state.tail.clear();
+        if let Some(file) = &mut state.file {
+            writeln!(file, "$ {} (in {})", command, cwd.display())?;
+        }
+        Ok(())
+    }
+
+    // Copies lines of `pipe` to the terminal and to the log, as they come
+    pub fn forward<R: Read + Send + 'static>(
+        &self,
+        pipe: R,
+        prefix: String,
+        is_stderr: bool,
+    ) -> JoinHandle<()> {
+        let state = self.state.clone();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(pipe);
+            let mut buffer = Vec::new();
+            while matches!(reader.read_until(b'\n', &mut buffer), Ok(read) if read > 0) {
+                let line = String::from_utf8_lossy(&buffer);
+                let line = line.trim_end_matches(['\n', '\r']);
+                if is_stderr {
+                    eprintln!("{} | {}", prefix, line);
+                } else {
+                    println!("{} | {}", prefix, line);
+                }
+                if let Ok(mut state) = state.lock() {
+                    state.record(line);
+                }
+                buffer.clear();
+            }
+        })
+    }
+
+    // Error of the last command, with whatever it printed before failing
+    pub fn failure(&self, error: Error) -> anyhow::Error {
+        let state = self.state.lock().unwrap();
+        let path = state.path.clone().unwrap_or_default();
+        let tail = match state.tail.is_empty() {
+            true => "(nothing)".to_string(),
+            false => Vec::from(state.tail.clone()).join("\n"),
+        };
+        anyhow::Error::new(Error::CommandOutput(path, tail)).context(error)
+    }
+}
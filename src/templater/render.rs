@@ -0,0 +1,385 @@
+use anyhow::{Context, Result};
+use heck::{ToKebabCase, ToLowerCamelCase, ToShoutySnakeCase, ToSnakeCase, ToUpperCamelCase};
+use globset::GlobMatcher;
+use minijinja::{syntax::SyntaxConfig, AutoEscape, Environment, ErrorKind, UndefinedBehavior};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tar::{Archive, EntryType};
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs::File,
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use super::{
+    error::Error,
+    selection::{self, Selection},
+};
+
+pub type Variables = BTreeMap<String, Value>;
+
+// Extensions that are never worth looking into
+const BINARY_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "bmp", "ico", "webp", "psd", "ttf", "otf", "woff", "woff2",
+    "eot", "so", "dll", "dylib", "a", "lib", "o", "obj", "exe", "class", "jar", "wasm", "zip",
+    "gz", "tgz", "xz", "bz2", "7z", "pdf", "mp3", "ogg", "wav", "mp4", "mkv",
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Delimiters {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variable: Option<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<(String, String)>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RenderRules {
+    #[serde(default)]
+    pub delimiters: Delimiters,
+    // Files with contents copied verbatim - their names are still rendered
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub raw: Vec<String>,
+}
+
+impl RenderRules {
+    pub fn check(&self) -> Result<()> {
+        Renderer::new(Variables::new(), self)?;
+        Ok(())
+    }
+
+    fn syntax(&self) -> Result<SyntaxConfig> {
+        let mut builder = SyntaxConfig::builder();
+        if let Some((start, end)) = &self.delimiters.variable {
+            builder.variable_delimiters(start.clone(), end.clone());
+        }
+        if let Some((start, end)) = &self.delimiters.block {
+            builder.block_delimiters(start.clone(), end.clone());
+        }
+        if let Some((start, end)) = &self.delimiters.comment {
+            builder.comment_delimiters(start.clone(), end.clone());
+        }
+        builder.build().context("Invalid delimiters")
+    }
+}
+
+pub struct Renderer {
+    env: Environment<'static>,
+    variables: Variables,
+    raw: Vec<GlobMatcher>,
+    verbatim: bool,
+    placeholder: Option<String>,
+}
+
+impl Renderer {
+    pub fn new(variables: Variables, rules: &RenderRules) -> Result<Self> {
+        let raw = rules
+            .raw
+            .iter()
+            .map(|pattern| selection::glob(pattern))
+            .collect::<Result<Vec<GlobMatcher>>>()?;
+
+        let mut env = Environment::new();
+        env.set_syntax(rules.syntax()?);
+        // Files without placeholders have to come out byte-for-byte identical
+        env.set_keep_trailing_newline(true);
+        env.set_auto_escape_callback(|_| AutoEscape::None);
+        // A typo in a placeholder is an error, not an empty string
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        env.add_filter("snake_case", |value: String| value.to_snake_case());
+        env.add_filter("camel_case", |value: String| value.to_lower_camel_case());
+        env.add_filter("pascal_case", |value: String| value.to_upper_camel_case());
+        env.add_filter("kebab_case", |value: String| value.to_kebab_case());
+        env.add_filter("upper_snake_case", |value: String| value.to_shouty_snake_case());
+
+        Ok(Renderer {
+            env,
+            variables,
+            raw,
+            verbatim: false,
+            placeholder: None,
+        })
+    }
+
+    // Templates made before files were rendered are copied as they are, names included
+    pub fn verbatim(mut self) -> Self {
+        self.verbatim = true;
+        self
+    }
+
+    // Previews can't know everything yet, like values set by hook scripts. Anything undefined is
+    // shown as `placeholder` there, instead of failing
+    pub fn placeholder(mut self, placeholder: &str) -> Self {
+        self.env.set_undefined_behavior(UndefinedBehavior::Chainable);
+        self.placeholder = Some(placeholder.to_string());
+        self
+    }
+
+    pub fn render_str(&self, name: &str, source: &str) -> Result<String> {
+        let mut variables = Cow::Borrowed(&self.variables);
+        if let Some(placeholder) = &self.placeholder {
+            for undefined in self.undefined_names(name, source) {
+                variables.to_mut().insert(undefined, placeholder.as_str().into());
+            }
+        }
+        self.env
+            .render_named_str(name, source, &*variables)
+            .map_err(|e| {
+                let undefined = match e.kind() {
+                    ErrorKind::UndefinedError => self.undefined_names(name, source),
+                    _ => Vec::new(),
+                };
+                let message = match e.detail() {
+                    _ if !undefined.is_empty() => format!("{}: {}", e.kind(), undefined.join(", ")),
+                    Some(detail) => format!("{}: {}", e.kind(), detail),
+                    None => e.kind().to_string(),
+                };
+                Error::RenderTemplate(name.to_string(), e.line().unwrap_or(0), message).into()
+            })
+    }
+
+    // Names the source uses, that have no value
+    fn undefined_names(&self, name: &str, source: &str) -> Vec<String> {
+        let template = match self.env.template_from_named_str(name, source) {
+            Ok(template) => template,
+            Err(_) => return Vec::new(),
+        };
+        let mut names = template
+            .undeclared_variables(false)
+            .into_iter()
+            .filter(|name| {
+                !self.variables.contains_key(name)
+                    && !self.env.globals().any(|(global, _)| global == name)
+            })
+            .collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    // Raw and binary files are passed through untouched, as is anything that isn't valid UTF-8
+    pub fn render_file(&self, path: &Path, data: Vec<u8>) -> Result<Vec<u8>> {
+        let relative = path.strip_prefix(".").unwrap_or(path);
+        if self.verbatim || self.raw.iter().any(|matcher| matcher.is_match(relative)) || is_binary(relative, &data) {
+            return Ok(data);
+        }
+        match String::from_utf8(data) {
+            Ok(text) => Ok(self.render_str(&relative.display().to_string(), &text)?.into_bytes()),
+            Err(e) => Ok(e.into_bytes()),
+        }
+    }
+
+    // Path is rendered as a whole, so a single placeholder may expand to several directories
+    pub fn render_path(&self, path: &Path) -> Result<PathBuf> {
+        if self.verbatim {
+            return Ok(path.to_path_buf());
+        }
+        let source = path.to_string_lossy();
+        Ok(PathBuf::from(self.render_str(&source, &source)?))
+    }
+
+    pub fn evaluate(&self, expression: &str) -> Result<bool> {
+        let result = self
+            .env
+            .compile_expression(expression)
+            .and_then(|compiled| compiled.eval(&self.variables))
+            .context(format!("Failed to evaluate condition: {}", expression))?;
+        Ok(result.is_true())
+    }
+
+    // Lets definitions be checked before any variables are known
+    pub fn check_expression(expression: &str) -> Result<()> {
+        Environment::new()
+            .compile_expression(expression)
+            .context(format!("Invalid condition: {}", expression))?;
+        Ok(())
+    }
+
+    // Renders every selected entry of the archive and hands it over to `visit`. Writing the
+    // project, previewing it and everything else that needs rendered files goes through here
+    pub fn render_archive<R: Read>(
+        &self,
+        archive: &mut Archive<R>,
+        selection: &mut Selection,
+        mut visit: impl FnMut(RenderedEntry) -> Result<()>,
+    ) -> Result<()> {
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let entry_type = entry.header().entry_type();
+            if !selection.includes(&path, entry_type.is_dir()) {
+                continue;
+            }
+            let rendered_path = self.render_path(&path)?;
+            let relative = safe_join(Path::new(""), &rendered_path)?;
+
+            match entry_type {
+                EntryType::Directory => {
+                    if !relative.as_os_str().is_empty() {
+                        visit(RenderedEntry::Directory(relative))?;
+                    }
+                }
+                EntryType::Regular => {
+                    if relative.as_os_str().is_empty() {
+                        return Err(Error::UnsafePath(rendered_path).into());
+                    }
+                    let mut data = Vec::new();
+                    entry.read_to_end(&mut data)?;
+                    visit(RenderedEntry::File {
+                        path: relative,
+                        data: self.render_file(&path, data)?,
+                        mode: entry.header().mode()?,
+                    })?;
+                }
+                // Templates are built from plain files and directories only
+                other => log::warn!("Skipping unsupported entry {} ({:?})", path.display(), other),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn variables(&self) -> &Variables {
+        &self.variables
+    }
+
+    pub fn set(&mut self, name: String, value: Value) {
+        self.variables.insert(name, value);
+    }
+}
+
+// Path is relative to the project root, and already checked not to leave it
+pub enum RenderedEntry {
+    Directory(PathBuf),
+    File {
+        path: PathBuf,
+        data: Vec<u8>,
+        mode: u32,
+    },
+}
+
+impl RenderedEntry {
+    pub fn path(&self) -> &Path {
+        match self {
+            RenderedEntry::Directory(path) => path,
+            RenderedEntry::File { path, .. } => path,
+        }
+    }
+
+    pub fn write(&self, dest: &Path) -> Result<()> {
+        let target = dest.join(self.path());
+        match self {
+            RenderedEntry::Directory(_) => std::fs::create_dir_all(&target)
+                .context(format!("Failed to create directory: {}", target.display())),
+            RenderedEntry::File { data, mode, .. } => {
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut file = File::create(&target)
+                    .context(format!("Failed to create file: {}", target.display()))?;
+                file.write_all(data)?;
+                set_mode(&file, *mode)
+            }
+        }
+    }
+}
+
+// Same heuristic git uses - text files don't contain NUL bytes
+fn is_binary(path: &Path, data: &[u8]) -> bool {
+    let known = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| BINARY_EXTENSIONS.contains(&extension.as_str()));
+    known || data.iter().take(8000).any(|byte| *byte == 0)
+}
+
+pub fn safe_join(dest: &Path, path: &Path) -> Result<PathBuf> {
+    let mut target = dest.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => target.push(part),
+            Component::CurDir => {}
+            _ => return Err(Error::UnsafePath(path.to_path_buf()).into()),
+        }
+    }
+    Ok(target)
+}
+
+#[cfg(unix)]
+fn set_mode(file: &File, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_file: &File, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_join_stays_inside() {
+        let dest = Path::new("project");
+        assert_eq!(safe_join(dest, Path::new("a/b.txt")).unwrap(), dest.join("a/b.txt"));
+        assert_eq!(safe_join(dest, Path::new("./a/./b")).unwrap(), dest.join("a/b"));
+        assert_eq!(safe_join(dest, Path::new("")).unwrap(), dest);
+    }
+
+    #[test]
+    fn safe_join_rejects_parent_dirs() {
+        assert!(safe_join(Path::new("project"), Path::new("../outside")).is_err());
+        assert!(safe_join(Path::new("project"), Path::new("a/../../outside")).is_err());
+        assert!(safe_join(Path::new("project"), Path::new("a/..")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn safe_join_rejects_absolute_paths() {
+        assert!(safe_join(Path::new("project"), Path::new("/etc/passwd")).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn safe_join_rejects_absolute_paths() {
+        assert!(safe_join(Path::new("project"), Path::new("C:\\Windows")).is_err());
+        assert!(safe_join(Path::new("project"), Path::new("\\Windows")).is_err());
+    }
+
+    #[test]
+    fn undefined_placeholder_is_named() {
+        let mut variables = Variables::new();
+        variables.insert("name".to_string(), "x".into());
+        let renderer = Renderer::new(variables, &RenderRules::default()).unwrap();
+        assert_eq!(renderer.render_str("f", "{{ name }}").unwrap(), "x");
+        let error = renderer.render_str("f", "{{ nme }}").unwrap_err().to_string();
+        assert!(error.contains("nme"), "{}", error);
+    }
+
+    #[test]
+    fn verbatim_renderer_copies_everything() {
+        let renderer = Renderer::new(Variables::new(), &RenderRules::default())
+            .unwrap()
+            .verbatim();
+        let data = b"n=${#arr[@]} {{ x }}".to_vec();
+        assert_eq!(renderer.render_file(Path::new("s.sh"), data.clone()).unwrap(), data);
+        assert_eq!(renderer.render_path(Path::new("{{ x }}/f")).unwrap(), Path::new("{{ x }}/f"));
+    }
+
+    #[test]
+    fn placeholder_stands_in_for_undefined() {
+        let mut variables = Variables::new();
+        variables.insert("name".to_string(), "x".into());
+        let renderer = Renderer::new(variables, &RenderRules::default())
+            .unwrap()
+            .placeholder("<later>");
+        assert_eq!(renderer.render_str("f", "{{ name }} {{ from_hook }}").unwrap(), "x <later>");
+        assert_eq!(renderer.render_str("f", "{% if from_hook %}y{% endif %}").unwrap(), "y");
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+use tar::{Archive, EntryType};
+
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    commands::{Step, TemplateCommand},
+    error::Error,
+    output::CommandLog,
+    render::Variables,
+};
+
+// Template directory that never makes it into expanded projects
+pub const RESERVED_DIR: &str = ".templater";
+// Scripts named after a phase (`post_expand.sh`, `pre_expand.ps1`, ...) are run in it
+pub const HOOKS_DIR: &str = ".templater/hooks";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    PreCreate,
+    PreExpand,
+    PostExpand,
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::PreCreate => "pre_create",
+            Phase::PreExpand => "pre_expand",
+            Phase::PostExpand => "post_expand",
+        }
+    }
+}
+
+pub struct Script {
+    pub name: String,
+    pub phase: Phase,
+    pub data: Vec<u8>,
+}
+
+impl Script {
+    // Anything that isn't named after a phase, or can't run on this platform, is left alone
+    fn new(name: String, data: Vec<u8>) -> Option<Self> {
+        let stem = name.split('.').next()?;
+        let phase = [Phase::PreCreate, Phase::PreExpand, Phase::PostExpand]
+            .into_iter()
+            .find(|phase| phase.name() == stem)?;
+        if !runs_here(&name) {
+            return None;
+        }
+        Some(Script { name, phase, data })
+    }
+
+    // Values written to $TEMPLATER_OUTPUT as KEY=VALUE lines are returned as new variables
+    pub fn run(
+        &self,
+        cwd: &Path,
+        envs: &HashMap<String, String>,
+        log: &CommandLog,
+    ) -> Result<Variables> {
+        let dir = tempfile::tempdir().context("Failed to create directory for hook script")?;
+        let path = dir.path().join(&self.name);
+        std::fs::write(&path, &self.data)?;
+        make_executable(&path)?;
+        let output = dir.path().join("output");
+        std::fs::write(&output, "")?;
+
+        let mut envs = envs.clone();
+        envs.insert("TEMPLATER_OUTPUT".to_string(), output.display().to_string());
+        let status = Step::from(self.command(path)).run(cwd, &envs, log)?;
+        if !status.success() {
+            return Err(log.failure(Error::CreateTemplate(self.to_string())));
+        }
+
+        let output = std::fs::read_to_string(&output)?;
+        let mut variables = Variables::new();
+        for line in output.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    variables.insert(key.trim().to_string(), value.into());
+                }
+                _ => return Err(Error::HookOutput(self.to_string(), line.to_string()).into()),
+            }
+        }
+        Ok(variables)
+    }
+
+    // Interpreter is picked by extension, scripts without one are run directly
+    fn command(&self, path: PathBuf) -> TemplateCommand {
+        let path = path.display().to_string();
+        let (program, args): (&str, &[&str]) = match extension(&self.name) {
+            Some("sh") => ("sh", &[]),
+            Some("bash") => ("bash", &[]),
+            Some("py") if cfg!(windows) => ("python", &[]),
+            Some("py") => ("python3", &[]),
+            Some("ps1") => ("powershell", &["-NoProfile", "-ExecutionPolicy", "Bypass", "-File"]),
+            Some("bat") | Some("cmd") => ("cmd", &["/C"]),
+            _ => {
+                return TemplateCommand::Program {
+                    program: path,
+                    args: Vec::new(),
+                }
+            }
+        };
+        TemplateCommand::Program {
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).chain([path]).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", HOOKS_DIR, self.name)
+    }
+}
+
+pub fn from_archive<R: Read>(archive: &mut Archive<R>) -> Result<Vec<Script>> {
+    let mut scripts = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let path = path.strip_prefix(".").unwrap_or(&path);
+        if entry.header().entry_type() != EntryType::Regular
+            || path.parent() != Some(Path::new(HOOKS_DIR))
+        {
+            continue;
+        }
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        scripts.extend(Script::new(name, data));
+    }
+    scripts.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(scripts)
+}
+
+pub fn from_dir(dir: &Path) -> Result<Vec<Script>> {
+    let hooks = dir.join(HOOKS_DIR);
+    if !hooks.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut scripts = Vec::new();
+    for entry in std::fs::read_dir(&hooks)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let data = std::fs::read(&path)
+            .context(format!("Failed to read hook script: {}", path.display()))?;
+        scripts.extend(Script::new(name, data));
+    }
+    scripts.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(scripts)
+}
+
+pub fn for_phase(scripts: &[Script], phase: Phase) -> Vec<&Script> {
+    scripts.iter().filter(|script| script.phase == phase).collect()
+}
+
+fn extension(name: &str) -> Option<&str> {
+    Path::new(name).extension().and_then(|extension| extension.to_str())
+}
+
+// Templates can ship e.g. `post_expand.sh` and `post_expand.ps1`, and get the right one
+fn runs_here(name: &str) -> bool {
+    match extension(name) {
+        Some("bat") | Some("cmd") | Some("ps1") => cfg!(windows),
+        None | Some("sh") | Some("bash") => !cfg!(windows),
+        _ => true,
+    }
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use super::error::Error;
+
+// Values of std::env::consts, anything else is most likely a typo
+const KNOWN_OS: &[&str] = &[
+    "linux", "macos", "windows", "ios", "android", "freebsd", "dragonfly", "netbsd", "openbsd",
+    "solaris", "illumos", "haiku",
+];
+const KNOWN_FAMILIES: &[&str] = &["unix", "windows", "wasm"];
+
+pub fn glob(pattern: &str) -> Result<GlobMatcher> {
+    let mut builder = GlobBuilder::new(pattern);
+    builder.case_insensitive(true);
+    builder
+        .build()
+        .context(format!("Failed to build glob pattern: {}", pattern))
+        .map(|glob| glob.compile_matcher())
+}
+
+// Decides which archive entries make it into the project. Patterns are matched against paths
+// relative to the template root, and a skipped directory takes all of its contents with it
+#[derive(Default)]
+pub struct Selection {
+    excluded: Vec<GlobMatcher>,
+    included: Vec<GlobMatcher>,
+    skipped_dirs: Vec<PathBuf>,
+}
+
+impl Selection {
+    pub fn exclude(&mut self, pattern: &str) -> Result<()> {
+        self.excluded.push(glob(pattern)?);
+        Ok(())
+    }
+
+    // Once anything is included, everything else is left out. Included directory brings all
+    // of its contents, and parents of included files are created when they are written
+    pub fn include(&mut self, pattern: &str) -> Result<()> {
+        self.included.push(glob(pattern)?);
+        Ok(())
+    }
+
+    pub fn includes(&mut self, path: &Path, is_dir: bool) -> bool {
+        let path = path.strip_prefix(".").unwrap_or(path);
+        if path.as_os_str().is_empty() {
+            return true;
+        }
+        if self.skipped_dirs.iter().any(|dir| path.starts_with(dir)) {
+            return false;
+        }
+
+        let excluded = self.excluded.iter().any(|matcher| matcher.is_match(path));
+        if excluded && is_dir {
+            self.skipped_dirs.push(path.to_path_buf());
+        }
+        !excluded && self.is_included(path)
+    }
+
+    fn is_included(&self, path: &Path) -> bool {
+        self.included.is_empty()
+            || path
+                .ancestors()
+                .filter(|path| !path.as_os_str().is_empty())
+                .any(|path| self.included.iter().any(|matcher| matcher.is_match(path)))
+    }
+}
+
+// Empty lists match everything, so only the constrained part has to be spelled out
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Platform {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub os: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub family: Vec<String>,
+}
+
+impl Platform {
+    pub fn is_any(&self) -> bool {
+        self.os.is_empty() && self.family.is_empty()
+    }
+
+    pub fn is_current(&self) -> bool {
+        let matches = |values: &[String], current: &str| {
+            values.is_empty() || values.iter().any(|value| value.eq_ignore_ascii_case(current))
+        };
+        matches(&self.os, std::env::consts::OS) && matches(&self.family, std::env::consts::FAMILY)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        let known = |values: &[String], known: &[&str], kind: &str| {
+            match values
+                .iter()
+                .find(|value| !known.contains(&value.to_lowercase().as_str()))
+            {
+                Some(value) => Err(Error::InvalidArgument(format!(
+                    "Unknown {} {}, expected one of: {}",
+                    kind,
+                    value,
+                    known.join(", ")
+                ))),
+                None => Ok(()),
+            }
+        };
+        known(&self.os, KNOWN_OS, "os")?;
+        known(&self.family, KNOWN_FAMILIES, "family")?;
+        Ok(())
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_any() {
+            return write!(f, "Any");
+        }
+        let parts = [&self.os, &self.family]
+            .into_iter()
+            .filter(|values| !values.is_empty())
+            .map(|values| values.join(" | "))
+            .collect::<Vec<String>>();
+        write!(f, "{}", parts.join(" & "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn everything_is_included_by_default() {
+        let mut selection = Selection::default();
+        assert!(selection.includes(Path::new("./src/main.rs"), false));
+        assert!(selection.includes(Path::new("."), true));
+    }
+
+    #[test]
+    fn excluded_directory_takes_its_contents() {
+        let mut selection = Selection::default();
+        selection.exclude("docs").unwrap();
+        assert!(!selection.includes(Path::new("./docs"), true));
+        assert!(!selection.includes(Path::new("./docs/index.md"), false));
+        assert!(selection.includes(Path::new("./docs.md"), false));
+    }
+
+    #[test]
+    fn excluded_file_pattern_leaves_directories() {
+        let mut selection = Selection::default();
+        selection.exclude("**/*.bat").unwrap();
+        assert!(selection.includes(Path::new("scripts"), true));
+        assert!(!selection.includes(Path::new("scripts/build.bat"), false));
+        assert!(selection.includes(Path::new("scripts/build.sh"), false));
+    }
+
+    #[test]
+    fn included_directory_brings_its_contents() {
+        let mut selection = Selection::default();
+        selection.include(".github").unwrap();
+        assert!(selection.includes(Path::new("./.github"), true));
+        assert!(selection.includes(Path::new("./.github/workflows/ci.yml"), false));
+        assert!(!selection.includes(Path::new("./src"), true));
+        assert!(!selection.includes(Path::new("./readme.md"), false));
+    }
+
+    #[test]
+    fn included_files_dont_bring_other_directories() {
+        let mut selection = Selection::default();
+        selection.include("src/**/*.c").unwrap();
+        // Directories are left out, but files inside are still looked at
+        assert!(!selection.includes(Path::new("src"), true));
+        assert!(selection.includes(Path::new("src/sub/a.c"), false));
+        assert!(!selection.includes(Path::new("src/sub/a.h"), false));
+    }
+
+    #[test]
+    fn exclusion_beats_inclusion() {
+        let mut selection = Selection::default();
+        selection.include("src").unwrap();
+        selection.exclude("src/generated").unwrap();
+        assert!(selection.includes(Path::new("src/main.c"), false));
+        assert!(!selection.includes(Path::new("src/generated"), true));
+        assert!(!selection.includes(Path::new("src/generated/a.c"), false));
+    }
+
+    #[test]
+    fn platform_display() {
+        let platform = Platform {
+            os: vec!["linux".to_string(), "macos".to_string()],
+            family: vec!["unix".to_string()],
+        };
+        assert_eq!(platform.to_string(), "linux | macos & unix");
+        assert_eq!(Platform::default().to_string(), "Any");
+        assert!(Platform::default().is_current());
+        assert!(platform.check().is_ok());
+        assert!(Platform { os: vec!["linx".to_string()], family: Vec::new() }.check().is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use anyhow::{Context, Result};
+use diffy::DiffOptions;
+
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    error::Error,
+    manifest::{self, Manifest},
+    render::RenderedEntry,
+    variables, Template, Templater,
+};
+
+// What happens to a file, given its version in the old revision (base), in the project (ours)
+// and in the new revision (theirs)
+#[derive(Debug, PartialEq)]
+enum Action {
+    Nothing,
+    Add,
+    Update,
+    Remove,
+    Merge,
+    // Removed from the template, but changed in the project
+    KeepChanged,
+    // Changed in the template, but deleted from the project
+    SkipDeleted,
+}
+
+fn action(base: Option<&[u8]>, ours: Option<&[u8]>, theirs: Option<&[u8]>) -> Action {
+    if base == theirs {
+        return Action::Nothing;
+    }
+    match (base, ours, theirs) {
+        (_, ours, Some(theirs)) if ours == Some(theirs) => Action::Nothing,
+        (Some(base), Some(ours), None) if ours == base => Action::Remove,
+        (_, Some(_), None) => Action::KeepChanged,
+        (_, None, None) => Action::Nothing,
+        (None, None, Some(_)) => Action::Add,
+        (Some(_), None, Some(_)) => Action::SkipDeleted,
+        (Some(base), Some(ours), Some(_)) if ours == base => Action::Update,
+        (_, Some(_), Some(_)) => Action::Merge,
+    }
+}
+
+// Result of merging changes of both sides of a file
+#[derive(Debug, PartialEq)]
+enum Merged {
+    Clean(Vec<u8>),
+    // Project's file with conflict markers
+    Conflict(Vec<u8>),
+    // Project's file stays as it is, this goes to `<file>.rej`
+    Rejected(Vec<u8>),
+}
+
+#[derive(Default)]
+struct Summary {
+    added: usize,
+    updated: usize,
+    merged: usize,
+    removed: usize,
+    conflicts: Vec<PathBuf>,
+}
+
+impl Templater {
+    // Three-way merge: what changed between the revision the project was expanded from and the
+    // current one is applied to the project, keeping changes made to it in the meantime
+    pub(super) fn update_project(&self, dir: &Path, reject: bool) -> Result<()> {
+        let manifest = Manifest::load(dir)?;
+        let name = manifest.template.clone();
+        let template: Template = match self.db.get(&name)? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => return Err(Error::TemplateNotFound(name).into()),
+        };
+        if !self.is_origin(&manifest, &template)? {
+            return Err(Error::TemplateMismatch(name, manifest.revision).into());
+        }
+        if template.revision == manifest.revision {
+            log::info!("Project is up to date with revision {} of {}", template.revision, name);
+            return Ok(());
+        }
+
+        let old: Template = match self
+            .db
+            .open_tree("history")?
+            .get(Self::history_key(&name, manifest.revision))?
+        {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => return Err(Error::RevisionNotFound(name, manifest.revision).into()),
+        };
+
+        // Newer revision may declare variables the project has no value for yet, and secrets
+        // aren't in the manifest, so both revisions may need some values asked for again
+        let interactive = std::io::stdin().is_terminal();
+        let mut values = manifest.variables.clone();
+        values.extend(variables::resolve(&template.variables, &manifest.variables, interactive)?);
+        let mut old_values = manifest.variables.clone();
+        old_values.extend(variables::resolve(&old.variables, &values, interactive)?);
+        values.insert("template_version".to_string(), template.revision.into());
+
+        let base = self.render_files(&old, &self.history_path(&name, old.revision), old_values)?;
+        let theirs = self.render_files(&template, &self.archive_path(&name), values.clone())?;
+
+        let mut summary = Summary::default();
+        let mut paths = base.keys().chain(theirs.keys()).collect::<Vec<&PathBuf>>();
+        paths.sort();
+        paths.dedup();
+        for path in paths {
+            let base = base.get(path).map(|(data, _)| data.as_slice());
+            let (theirs, mode) = match theirs.get(path) {
+                Some((data, mode)) => (Some(data.as_slice()), *mode),
+                None => (None, 0),
+            };
+            if base == theirs {
+                continue;
+            }
+            let target = dir.join(path);
+            let ours = match target.is_file() {
+                true => Some(
+                    std::fs::read(&target)
+                        .context(format!("Failed to read file: {}", target.display()))?,
+                ),
+                false => None,
+            };
+
+            match action(base, ours.as_deref(), theirs) {
+                Action::Nothing => {}
+                Action::Remove => {
+                    std::fs::remove_file(&target)?;
+                    log::info!("Removed: {}", path.display());
+                    summary.removed += 1;
+                }
+                Action::KeepChanged => {
+                    log::warn!("Kept {}, it was removed from template, but changed here", path.display());
+                }
+                Action::Add => {
+                    RenderedEntry::File {
+                        path: path.clone(),
+                        data: theirs.unwrap_or_default().to_vec(),
+                        mode,
+                    }
+                    .write(dir)?;
+                    log::info!("Added: {}", path.display());
+                    summary.added += 1;
+                }
+                Action::SkipDeleted => {
+                    log::warn!("Skipped {}, it was deleted here", path.display());
+                }
+                Action::Update => {
+                    std::fs::write(&target, theirs.unwrap_or_default())?;
+                    log::info!("Updated: {}", path.display());
+                    summary.updated += 1;
+                }
+                Action::Merge => {
+                    let merged = merge_contents(
+                        &path.display().to_string(),
+                        base.unwrap_or_default(),
+                        ours.as_deref().unwrap_or_default(),
+                        theirs.unwrap_or_default(),
+                        reject,
+                    );
+                    match merged {
+                        Merged::Clean(data) => {
+                            std::fs::write(&target, data)?;
+                            log::info!("Merged: {}", path.display());
+                            summary.merged += 1;
+                        }
+                        Merged::Conflict(data) => {
+                            std::fs::write(&target, data)?;
+                            log::warn!("Conflict: {}", path.display());
+                            summary.conflicts.push(path.clone());
+                        }
+                        Merged::Rejected(data) => {
+                            std::fs::write(format!("{}.rej", target.display()), data)?;
+                            log::warn!("Conflict: {}", path.display());
+                            summary.conflicts.push(path.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        // Nothing is run, commands stay the ones the project was generated with
+        Manifest::new(
+            &name,
+            template.revision,
+            &self.archive_path(&name),
+            &values,
+            &template.variables,
+            manifest.commands,
+        )?
+        .save(dir)?;
+
+        log::info!(
+            "Updated project to revision {} of {}: {} added, {} updated, {} merged, {} removed, {} conflicts",
+            template.revision,
+            name,
+            summary.added,
+            summary.updated,
+            summary.merged,
+            summary.removed,
+            summary.conflicts.len()
+        );
+        if !summary.conflicts.is_empty() {
+            let resolve = match reject {
+                true => "apply changes from .rej files by hand",
+                false => "resolve conflict markers in them",
+            };
+            log::warn!("Some files couldn't be merged, {}:", resolve);
+            for path in &summary.conflicts {
+                log::warn!("  {}", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    // Templates deleted and created again start counting revisions from 1, so the name and
+    // revision alone don't say the project came from this one. Manifests from before digests
+    // were recorded are trusted
+    pub(super) fn is_origin(&self, manifest: &Manifest, template: &Template) -> Result<bool> {
+        if manifest.digest.is_empty() {
+            return Ok(true);
+        }
+        let archive = match manifest.revision == template.revision {
+            true => self.archive_path(&template.name),
+            false => self.history_path(&template.name, manifest.revision),
+        };
+        if !archive.exists() {
+            return Ok(true);
+        }
+        Ok(manifest::digest(&archive)? == manifest.digest)
+    }
+}
+
+// Conflicting files get conflict markers, or are left alone with template changes put to
+// `<file>.rej` - which is also where binary files go
+fn merge_contents(name: &str, base: &[u8], ours: &[u8], theirs: &[u8], reject: bool) -> Merged {
+    let texts = (
+        std::str::from_utf8(base),
+        std::str::from_utf8(ours),
+        std::str::from_utf8(theirs),
+    );
+    let (base, ours, theirs) = match texts {
+        (Ok(base), Ok(ours), Ok(theirs)) => (base, ours, theirs),
+        _ => return Merged::Rejected(theirs.to_vec()),
+    };
+
+    match diffy::merge(base, ours, theirs) {
+        Ok(merged) => Merged::Clean(merged.into_bytes()),
+        Err(_) if reject => {
+            let patch = DiffOptions::new()
+                .set_original_filename(name.to_string())
+                .set_modified_filename(name.to_string())
+                .create_patch(base, theirs);
+            Merged::Rejected(patch.to_string().into_bytes())
+        }
+        Err(conflicted) => Merged::Conflict(conflicted.into_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_template_leaves_file_alone() {
+        assert_eq!(action(Some(b"a"), Some(b"mine"), Some(b"a")), Action::Nothing);
+        assert_eq!(action(None, Some(b"mine"), None), Action::Nothing);
+    }
+
+    #[test]
+    fn same_change_on_both_sides_is_nothing_to_do() {
+        assert_eq!(action(Some(b"a"), Some(b"b"), Some(b"b")), Action::Nothing);
+        assert_eq!(action(None, Some(b"b"), Some(b"b")), Action::Nothing);
+    }
+
+    #[test]
+    fn new_file_is_added() {
+        assert_eq!(action(None, None, Some(b"b")), Action::Add);
+    }
+
+    #[test]
+    fn untouched_file_follows_template() {
+        assert_eq!(action(Some(b"a"), Some(b"a"), Some(b"b")), Action::Update);
+        assert_eq!(action(Some(b"a"), Some(b"a"), None), Action::Remove);
+    }
+
+    #[test]
+    fn changes_made_in_project_are_kept() {
+        assert_eq!(action(Some(b"a"), Some(b"mine"), None), Action::KeepChanged);
+        assert_eq!(action(Some(b"a"), None, Some(b"b")), Action::SkipDeleted);
+        assert_eq!(action(Some(b"a"), None, None), Action::Nothing);
+    }
+
+    #[test]
+    fn changes_on_both_sides_are_merged() {
+        assert_eq!(action(Some(b"a"), Some(b"mine"), Some(b"b")), Action::Merge);
+        // Project already had a file the template only now adds
+        assert_eq!(action(None, Some(b"mine"), Some(b"b")), Action::Merge);
+    }
+
+    #[test]
+    fn separate_changes_merge_cleanly() {
+        let base = b"1\n2\n3\n4\n5\n";
+        let merged = merge_contents("f", base, b"one\n2\n3\n4\n5\n", b"1\n2\n3\n4\nfive\n", false);
+        assert_eq!(merged, Merged::Clean(b"one\n2\n3\n4\nfive\n".to_vec()));
+    }
+
+    #[test]
+    fn overlapping_changes_get_conflict_markers() {
+        let merged = merge_contents("f", b"a\n", b"mine\n", b"theirs\n", false);
+        let text = match merged {
+            Merged::Conflict(data) => String::from_utf8(data).unwrap(),
+            other => panic!("expected conflict, got {:?}", other),
+        };
+        assert!(text.contains("<<<<<<<"));
+        assert!(text.contains("mine\n"));
+        assert!(text.contains("theirs\n"));
+    }
+
+    #[test]
+    fn rejected_conflict_is_a_patch_of_template_changes() {
+        let merged = merge_contents("dir/f", b"a\n", b"mine\n", b"theirs\n", true);
+        let text = match merged {
+            Merged::Rejected(data) => String::from_utf8(data).unwrap(),
+            other => panic!("expected rejection, got {:?}", other),
+        };
+        assert!(text.starts_with("--- dir/f\n+++ dir/f\n"));
+        assert!(text.contains("-a\n+theirs\n"));
+    }
+
+    #[test]
+    fn binary_files_are_rejected_whole() {
+        let merged = merge_contents("f", b"\xff\x00", b"\xfe\x00", b"\xfd\x00", false);
+        assert_eq!(merged, Merged::Rejected(b"\xfd\x00".to_vec()));
+    }
+}
@@ -0,0 +1,399 @@
+use anyhow::{Context, Result};
+use chrono::{Datelike, Local};
+use dialoguer::{Confirm, Input, Password, Select};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::{collections::HashMap, fmt::Display, io::Read, path::Path};
+
+use super::{error::Error, render::Variables};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VariableKind {
+    #[default]
+    String,
+    Bool,
+    Integer,
+    Choice,
+    List,
+}
+
+impl Display for VariableKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            VariableKind::String => "string",
+            VariableKind::Bool => "bool",
+            VariableKind::Integer => "integer",
+            VariableKind::Choice => "choice",
+            VariableKind::List => "list",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Variable {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: VariableKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub help: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<String>,
+    // Regex the whole value has to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validation: Option<String>,
+    // Not written to the project's manifest, and typed in without echo
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub secret: bool,
+}
+
+impl Variable {
+    // Catches broken declarations when the template is created, not when someone expands it
+    pub fn check(&self) -> Result<()> {
+        if self.kind == VariableKind::Choice && self.choices.is_empty() {
+            return Err(self.invalid("choice variable needs at least one entry in `choices`"));
+        }
+        if let Some(pattern) = &self.validation {
+            Self::compile(pattern)
+                .context(format!("Invalid validation pattern of variable {}", self.name))?;
+        }
+        if let Some(default) = &self.default {
+            self.validate(default)
+                .context(format!("Invalid default of variable {}", self.name))?;
+        }
+        Ok(())
+    }
+
+    // Strings are parsed according to the variable type, so command line input can be used directly
+    pub fn validate(&self, value: &Value) -> Result<Value> {
+        let value = match (self.kind, value) {
+            (_, Value::String(input)) => self.parse(input)?,
+            (VariableKind::Bool, Value::Bool(_)) => value.clone(),
+            (VariableKind::Integer, Value::Number(number)) if number.is_i64() => value.clone(),
+            (VariableKind::List, Value::Array(items)) if items.iter().all(Value::is_string) => {
+                value.clone()
+            }
+            _ => return Err(self.invalid(&format!("expected {}, got {}", self.kind, value))),
+        };
+
+        // Lists are checked item by item
+        let texts = match &value {
+            Value::Array(items) => items.iter().map(text).collect(),
+            other => vec![text(other)],
+        };
+        for text in texts {
+            if self.kind == VariableKind::Choice && !self.choices.contains(&text) {
+                return Err(self.invalid(&format!(
+                    "{} is not one of: {}",
+                    text,
+                    self.choices.join(", ")
+                )));
+            }
+            if let Some(pattern) = &self.validation {
+                if !Self::compile(pattern)?.is_match(&text) {
+                    return Err(self.invalid(&format!("{} does not match {}", text, pattern)));
+                }
+            }
+        }
+
+        Ok(value)
+    }
+
+    pub fn prompt(&self) -> Result<Value> {
+        let prompt = match &self.help {
+            Some(help) => format!("{} ({})", help, self.name),
+            None => self.name.clone(),
+        };
+
+        match self.kind {
+            VariableKind::Bool => {
+                let default = matches!(self.default, Some(Value::Bool(true)));
+                let answer = Confirm::new()
+                    .with_prompt(prompt)
+                    .default(default)
+                    .interact()?;
+                Ok(answer.into())
+            }
+            VariableKind::Choice => {
+                let default = self
+                    .default
+                    .as_ref()
+                    .and_then(|default| self.choices.iter().position(|c| *c == text(default)))
+                    .unwrap_or(0);
+                let index = Select::new()
+                    .with_prompt(prompt)
+                    .items(&self.choices)
+                    .default(default)
+                    .interact()?;
+                Ok(self.choices[index].clone().into())
+            }
+            _ if self.secret => {
+                // Nothing typed means the default, which can't be shown
+                let mut input = Password::new()
+                    .with_prompt(prompt)
+                    .validate_with(|input: &String| {
+                        if input.is_empty() && self.default.is_some() {
+                            return Ok(());
+                        }
+                        self.validate(&Value::String(input.clone()))
+                            .map(|_| ())
+                            .map_err(|e| e.to_string())
+                    });
+                if self.default.is_some() {
+                    input = input.allow_empty_password(true);
+                }
+                let answer = input.interact()?;
+                match (&self.default, answer.is_empty()) {
+                    (Some(default), true) => self.validate(default),
+                    _ => self.validate(&Value::String(answer)),
+                }
+            }
+            VariableKind::String | VariableKind::Integer | VariableKind::List => {
+                let mut input = Input::<String>::new()
+                    .with_prompt(prompt)
+                    .validate_with(|input: &String| {
+                        self.validate(&Value::String(input.clone()))
+                            .map(|_| ())
+                            .map_err(|e| e.to_string())
+                    });
+                if let Some(default) = &self.default {
+                    input = input.default(text(default));
+                }
+                let answer = input.interact_text()?;
+                self.validate(&Value::String(answer))
+            }
+        }
+    }
+
+    fn parse(&self, input: &str) -> Result<Value> {
+        match self.kind {
+            VariableKind::String | VariableKind::Choice => Ok(input.into()),
+            VariableKind::Bool => match input.to_lowercase().as_str() {
+                "true" | "yes" | "y" | "on" | "1" => Ok(true.into()),
+                "false" | "no" | "n" | "off" | "0" => Ok(false.into()),
+                _ => Err(self.invalid(&format!("{} is not a bool", input))),
+            },
+            VariableKind::Integer => input
+                .trim()
+                .parse::<i64>()
+                .map(Value::from)
+                .map_err(|_| self.invalid(&format!("{} is not an integer", input))),
+            // Comma separated, so lists can be given with --env too
+            VariableKind::List => Ok(input
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(Value::from)
+                .collect()),
+        }
+    }
+
+    fn compile(pattern: &str) -> Result<Regex> {
+        Ok(Regex::new(&format!("^(?:{})$", pattern))?)
+    }
+
+    fn invalid(&self, reason: &str) -> anyhow::Error {
+        Error::InvalidVariable(self.name.clone(), reason.to_string()).into()
+    }
+}
+
+// Supplied values win, anything else is asked for - or taken from defaults, when there is
+// nobody to ask. All problems are reported at once, so answer files can be fixed in one go
+pub fn resolve(declared: &[Variable], supplied: &Variables, interactive: bool) -> Result<Variables> {
+    let mut variables = Variables::new();
+    let mut problems = Vec::new();
+
+    for variable in declared {
+        let value = match supplied.get(&variable.name) {
+            Some(value) => variable.validate(value),
+            None if interactive => Ok(variable.prompt()?),
+            None => match &variable.default {
+                Some(default) => variable.validate(default),
+                None => Err(Error::MissingVariable(variable.name.clone()).into()),
+            },
+        };
+
+        match value {
+            Ok(value) => {
+                variables.insert(variable.name.clone(), value);
+            }
+            Err(e) => problems.push(e.to_string()),
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(Error::InvalidValues(problems).into());
+    }
+    Ok(variables)
+}
+
+// `-` reads from stdin. TOML is recognised by extension, or tried when stdin isn't JSON
+pub fn load_answers(path: &Path) -> Result<Variables> {
+    let is_stdin = path == Path::new("-");
+    let contents = if is_stdin {
+        let mut contents = String::new();
+        std::io::stdin()
+            .read_to_string(&mut contents)
+            .context("Couldn't read values from stdin")?;
+        contents
+    } else {
+        std::fs::read_to_string(path)
+            .context(format!("Couldn't read values file {}", path.display()))?
+    };
+
+    if is_toml(path) {
+        return toml::from_str(&contents).context("Provided file is not valid TOML");
+    }
+    match serde_json::from_str(&contents) {
+        Ok(answers) => Ok(answers),
+        Err(_) if is_stdin => toml::from_str(&contents).context("Values are neither JSON nor TOML"),
+        Err(e) => Err(e).context("Provided file is not valid JSON"),
+    }
+}
+
+pub fn save_answers(path: &Path, answers: &Variables) -> Result<()> {
+    let contents = if is_toml(path) {
+        toml::to_string_pretty(answers).context("Failed to serialize values")?
+    } else {
+        serde_json::to_string_pretty(answers).context("Failed to serialize values")?
+    };
+    std::fs::write(path, contents).context(format!("Couldn't write values file {}", path.display()))
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "toml")
+}
+
+// Variables every template gets, no matter what it declares. Anything supplied overrides them
+pub fn builtins(template_name: &str, template_version: u32, project_dir: &Path) -> Variables {
+    let now = Local::now();
+    let directory_name = project_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut variables = Variables::new();
+    variables.insert("project_name".to_string(), directory_name.clone().into());
+    variables.insert("directory_name".to_string(), directory_name.into());
+    variables.insert("date".to_string(), now.format("%Y-%m-%d").to_string().into());
+    variables.insert("year".to_string(), now.year().into());
+    variables.insert("author_name".to_string(), author("TEMPLATER_AUTHOR_NAME", "user.name").into());
+    variables.insert("author_email".to_string(), author("TEMPLATER_AUTHOR_EMAIL", "user.email").into());
+    variables.insert("os".to_string(), std::env::consts::OS.into());
+    variables.insert("os_family".to_string(), std::env::consts::FAMILY.into());
+    variables.insert("template_name".to_string(), template_name.into());
+    variables.insert("template_version".to_string(), template_version.into());
+    variables
+}
+
+// Built-ins are passed to commands as TEMPLATER_<NAME>, with their values taken from
+// `variables`, so overrides apply there too
+pub fn exports(builtins: &Variables, variables: &Variables) -> HashMap<String, String> {
+    builtins
+        .iter()
+        .map(|(key, value)| {
+            let value = variables.get(key).unwrap_or(value);
+            (format!("TEMPLATER_{}", key.to_uppercase()), text(value))
+        })
+        .collect()
+}
+
+// Environment variable first, then git config. Empty when neither is set
+fn author(env: &str, git_key: &str) -> String {
+    if let Ok(value) = std::env::var(env) {
+        return value;
+    }
+    std::process::Command::new("git")
+        .args(["config", "--get", git_key])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default()
+}
+
+pub fn text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items.iter().map(text).collect::<Vec<_>>().join(", "),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable(json: &str) -> Variable {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn bool_from_strings() {
+        let flag = variable(r#"{"name": "flag", "type": "bool"}"#);
+        for input in ["true", "Yes", "y", "on", "1"] {
+            assert_eq!(flag.validate(&input.into()).unwrap(), Value::Bool(true), "{}", input);
+        }
+        for input in ["false", "NO", "n", "off", "0"] {
+            assert_eq!(flag.validate(&input.into()).unwrap(), Value::Bool(false), "{}", input);
+        }
+        assert!(flag.validate(&"maybe".into()).is_err());
+        assert!(flag.validate(&1.into()).is_err());
+    }
+
+    #[test]
+    fn integer_from_strings() {
+        let port = variable(r#"{"name": "port", "type": "integer"}"#);
+        assert_eq!(port.validate(&" 8080 ".into()).unwrap(), Value::from(8080));
+        assert_eq!(port.validate(&Value::from(-1)).unwrap(), Value::from(-1));
+        assert!(port.validate(&"80.5".into()).is_err());
+        assert!(port.validate(&Value::from(80.5)).is_err());
+    }
+
+    #[test]
+    fn invalid_choice() {
+        let edition =
+            variable(r#"{"name": "edition", "type": "choice", "choices": ["2018", "2021"]}"#);
+        assert_eq!(edition.validate(&"2021".into()).unwrap(), Value::from("2021"));
+        let error = edition.validate(&"2015".into()).unwrap_err().to_string();
+        assert!(error.contains("2015 is not one of: 2018, 2021"), "{}", error);
+        assert!(variable(r#"{"name": "empty", "type": "choice"}"#).check().is_err());
+    }
+
+    #[test]
+    fn validation_is_anchored() {
+        let name = variable(r#"{"name": "crate_name", "validation": "[a-z_]+"}"#);
+        assert!(name.validate(&"my_crate".into()).is_ok());
+        assert!(name.validate(&"My_crate".into()).is_err());
+        assert!(name.validate(&"my_crate!".into()).is_err());
+        // Alternatives are anchored as a whole
+        let either = variable(r#"{"name": "either", "validation": "a|b"}"#);
+        assert!(either.validate(&"b".into()).is_ok());
+        assert!(either.validate(&"ab".into()).is_err());
+        let features = variable(r#"{"name": "features", "type": "list", "validation": "[a-z]+"}"#);
+        assert!(features.validate(&"serde, tokio".into()).is_ok());
+        assert!(features.validate(&"serde, Tokio".into()).is_err());
+    }
+
+    #[test]
+    fn missing_value_without_default() {
+        let declared = [
+            variable(r#"{"name": "crate_name"}"#),
+            variable(r#"{"name": "port", "type": "integer", "default": 8080}"#),
+            variable(r#"{"name": "use_ci", "type": "bool"}"#),
+        ];
+        let resolved = resolve(&declared[1..2], &Variables::new(), false).unwrap();
+        assert_eq!(resolved["port"], Value::from(8080));
+
+        // Every problem is reported, not only the first one
+        let supplied = Variables::from([("use_ci".to_string(), Value::from("perhaps"))]);
+        let error = resolve(&declared, &supplied, false).unwrap_err().to_string();
+        assert!(error.contains("No value provided for variable crate_name"), "{}", error);
+        assert!(error.contains("perhaps is not a bool"), "{}", error);
+    }
+}